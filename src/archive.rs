@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm,
+};
+use argon2::Argon2;
+use dialoguer::{theme::ColorfulTheme, Password, Select};
+use snafu::ResultExt;
+
+use crate::{
+    atomic::write_atomic,
+    error::{CorruptFileErr, FsErr, Result},
+    layout::to_hex,
+    manager::{length_validator, unlock, Manager},
+};
+
+pub const ARCHIVE_PATH: &str = "pm.tar";
+const ARCHIVE_MAGIC: &[u8] = b"pm-archive\x01";
+const ARCHIVE_DIR: &str = "PassManager";
+
+impl Manager {
+    /// Writes the data dir, history included, as a tar encrypted with the
+    /// store key or an export passphrase. Either way the key is derived with
+    /// a salt of its own, so the archive opens without the store.
+    pub fn write_archive(&self, path: &Path) -> Result<()> {
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Encrypt the archive with")
+            .items(&["your key", "a separate export passphrase"])
+            .default(0)
+            .interact()?;
+
+        let passphrase = if selection == 0 {
+            let key = Password::with_theme(&ColorfulTheme::default())
+                .with_prompt("Your key")
+                .interact()?;
+            unlock(&self.store, &key)?;
+
+            key
+        } else {
+            Password::with_theme(&ColorfulTheme::default())
+                .with_prompt("Export passphrase")
+                .with_confirmation("Retype passphrase", "passphrases do not match")
+                .validate_with(|inp: &String| length_validator(inp))
+                .interact()?
+        };
+
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_dir_all(ARCHIVE_DIR, &self.data_dir)?;
+        let data = builder.into_inner()?;
+
+        let salt: [u8; 16] = rand::random();
+        let nonce: [u8; 12] = rand::random();
+        let ciphertext =
+            archive_cipher(&passphrase, &salt)?.encrypt(&nonce.into(), data.as_slice())?;
+
+        write_atomic(path, &[ARCHIVE_MAGIC, &salt, &nonce, &ciphertext].concat())
+    }
+}
+
+/// Re-creates the data dir from an archive written by nuke.
+pub fn restore_archive(data_dir: &Path, path: &Path) -> Result<String> {
+    let buf = std::fs::read(path).context(FsErr {
+        path: path.display().to_string(),
+    })?;
+
+    let corrupt = || {
+        CorruptFileErr {
+            path: path.display().to_string(),
+        }
+        .build()
+    };
+
+    let rest = buf.strip_prefix(ARCHIVE_MAGIC).ok_or_else(corrupt)?;
+    let (salt, rest) = rest.split_at_checked(16).ok_or_else(corrupt)?;
+    let (nonce, ciphertext) = rest.split_at_checked(12).ok_or_else(corrupt)?;
+
+    let passphrase = Password::with_theme(&ColorfulTheme::default())
+        .with_prompt("Key or export passphrase of the archive")
+        .interact()?;
+    let data = archive_cipher(&passphrase, salt.try_into()?)?.decrypt(nonce.into(), ciphertext)?;
+
+    // unpacked next to the data dir first, so a failure leaves no half store
+    let parent = data_dir.parent().unwrap_or_else(|| Path::new("."));
+    let unpack_dir = parent.join(format!(
+        ".{ARCHIVE_DIR}-restore-{}",
+        to_hex(&rand::random::<[u8; 4]>())
+    ));
+
+    let result = tar::Archive::new(data.as_slice())
+        .unpack(&unpack_dir)
+        .context(FsErr {
+            path: unpack_dir.display().to_string(),
+        })
+        .and_then(|()| {
+            std::fs::rename(unpack_dir.join(ARCHIVE_DIR), data_dir).context(FsErr {
+                path: data_dir.display().to_string(),
+            })
+        });
+    let _ = std::fs::remove_dir_all(&unpack_dir);
+    result?;
+
+    Ok(format!(
+        "Successfully restored the store from {}",
+        path.display()
+    ))
+}
+
+fn archive_cipher(passphrase: &str, salt: &[u8; 16]) -> Result<Aes256Gcm> {
+    let mut key = [0u8; 32];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key)?;
+
+    Ok(Aes256Gcm::new(&key.into()))
+}
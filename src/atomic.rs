@@ -0,0 +1,106 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use snafu::ResultExt;
+
+use crate::{
+    error::{FsErr, Result},
+    layout::to_hex,
+};
+
+/// Replaces `path` with `data`. The data goes to a temp file in the same
+/// directory that is synced and renamed over `path`, so a crash leaves
+/// either the old or the new file, never a truncated one.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    write_unsynced(path, data)?;
+
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    sync_dir(dir)
+}
+
+/// [`write_atomic`] without syncing the directory, for writing many files
+/// into one directory followed by a single [`sync_dir`].
+pub fn write_unsynced(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = temp_path(path);
+
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+
+        std::fs::rename(&tmp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+
+    result.context(FsErr {
+        path: path.display().to_string(),
+    })
+}
+
+/// [`write_atomic`] that keeps the previous contents of `path` as
+/// `<path>.bak`.
+pub fn write_with_backup(path: &Path, data: &[u8]) -> Result<()> {
+    if path.exists() {
+        let mut bak_path = path.as_os_str().to_owned();
+        bak_path.push(".bak");
+        let bak_path = PathBuf::from(bak_path);
+
+        // A hard link keeps the old file alive under the new name without
+        // copying it, filesystems without links get a copy.
+        let link_path = temp_path(&bak_path);
+        if std::fs::hard_link(path, &link_path).is_ok() {
+            std::fs::rename(&link_path, &bak_path).context(FsErr {
+                path: bak_path.display().to_string(),
+            })?;
+        } else {
+            let old = std::fs::read(path).context(FsErr {
+                path: path.display().to_string(),
+            })?;
+            write_atomic(&bak_path, &old)?;
+        }
+    }
+
+    write_atomic(path, data)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(
+        ".{name}.tmp-{}",
+        to_hex(&rand::random::<[u8; 4]>())
+    ))
+}
+
+/// Makes renames and removals in `dir` durable, directories can only be
+/// synced on unix.
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    std::fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .context(FsErr {
+            path: dir.display().to_string(),
+        })
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
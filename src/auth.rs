@@ -0,0 +1,576 @@
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use base64ct::{Base64, Encoding};
+use dialoguer::{theme::ColorfulTheme, Password};
+use git2::{
+    CertificateCheckStatus, Cred, CredentialType, Error as GitError, ErrorCode, RemoteCallbacks,
+};
+use hmac::{Hmac, Mac};
+use owo_colors::OwoColorize;
+use sha1::Sha1;
+use snafu::OptionExt;
+use url::Url;
+
+use crate::{
+    config::{Config, KnownHosts},
+    error::{CommandErr, CredentialHelperErr, CredsErr, HostErr, Result, SplitErr},
+    user::Credentials,
+};
+
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+pub struct SshOptions {
+    pub key: Option<PathBuf>,
+    pub known_hosts: KnownHosts,
+}
+
+impl SshOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            key: config.ssh_key(),
+            known_hosts: config.known_hosts(),
+        }
+    }
+
+    fn key_files(&self) -> Vec<PathBuf> {
+        self.key.as_ref().map_or_else(
+            || {
+                dirs::home_dir()
+                    .map(|home| {
+                        DEFAULT_KEYS
+                            .iter()
+                            .map(|name| home.join(".ssh").join(name))
+                            .filter(|path| path.exists())
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            },
+            |key| vec![key.clone()],
+        )
+    }
+}
+
+pub struct CredentialHelper {
+    url: String,
+    filled: RefCell<Option<Credentials>>,
+}
+
+impl CredentialHelper {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            filled: RefCell::new(None),
+        }
+    }
+
+    pub fn fill(&self) -> Result<Credentials> {
+        let creds = parse_creds(&git_credential("fill", &self.describe(None), true)?)?;
+
+        *self.filled.borrow_mut() = Some(creds.clone());
+
+        Ok(creds)
+    }
+
+    /// Asks the configured helpers without ever prompting, fails when none
+    /// of them has credentials for the url.
+    pub fn lookup(&self) -> Result<Credentials> {
+        parse_creds(&git_credential("fill", &self.describe(None), false)?)
+    }
+
+    pub fn store(&self, creds: Credentials) {
+        *self.filled.borrow_mut() = Some(creds);
+    }
+
+    pub fn approve(&self) -> Result<()> {
+        if let Some(creds) = self.filled.take() {
+            git_credential("approve", &self.describe(Some(&creds)), true)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn reject(&self) {
+        if let Some(creds) = self.filled.take() {
+            let _ = git_credential("reject", &self.describe(Some(&creds)), true);
+        }
+    }
+
+    pub fn settle<T>(&self, result: Result<T, GitError>) -> Result<T> {
+        match result {
+            Ok(value) => {
+                // the operation went through, a helper that can't store the
+                // credentials only means they are asked for again
+                let _ = self.approve();
+                Ok(value)
+            }
+            Err(err) => {
+                if err.code() == ErrorCode::Auth {
+                    self.reject();
+                }
+                Err(err.into())
+            }
+        }
+    }
+
+    fn describe(&self, creds: Option<&Credentials>) -> String {
+        let mut description = String::new();
+
+        if let Ok(url) = Url::parse(&self.url) {
+            let _ = writeln!(description, "protocol={}", url.scheme());
+
+            if let Some(host) = url.host_str() {
+                let _ = writeln!(
+                    description,
+                    "host={host}{}",
+                    url.port()
+                        .map_or_else(String::new, |port| format!(":{port}"))
+                );
+            }
+
+            let path = url.path().trim_start_matches('/');
+            if !path.is_empty() {
+                let _ = writeln!(description, "path={path}");
+            }
+        }
+
+        if let Some(Credentials { username, password }) = creds {
+            let _ = writeln!(description, "username={username}\npassword={password}");
+        }
+
+        description.push('\n');
+
+        description
+    }
+}
+
+fn parse_creds(output: &str) -> Result<Credentials> {
+    let mut creds = (None, None);
+
+    for line in output.split_terminator('\n') {
+        let (k, v) = line.split_once('=').context(SplitErr)?;
+        if k == "username" {
+            creds.0 = Some(v.to_string());
+        } else if k == "password" {
+            creds.1 = Some(v.to_string());
+        }
+    }
+
+    match creds {
+        (Some(username), Some(password)) => Ok(Credentials { username, password }),
+        (None, Some(_)) => Err(CredsErr { key: "username" }.build()),
+        (Some(_), None) => Err(CredsErr { key: "password" }.build()),
+        (None, None) => Err(CredsErr {
+            key: "username, password",
+        }
+        .build()),
+    }
+}
+
+fn git_credential(action: &str, input: &str, interactive: bool) -> Result<String> {
+    let mut command = Command::new("git");
+    if !interactive {
+        // no terminal or askpass prompt, only what the helpers have stored
+        command
+            .args(["-c", "credential.interactive=false", "-c", "core.askPass="])
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .env_remove("GIT_ASKPASS")
+            .env_remove("SSH_ASKPASS");
+    }
+
+    let mut child = command
+        .args(["credential", action])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    child
+        .stdin
+        .take()
+        .context(CommandErr { fd: "stdin" })?
+        .write_all(input.as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(CredentialHelperErr {
+            action,
+            message: stderr
+                .lines()
+                .last()
+                .map_or_else(|| output.status.to_string(), str::to_string),
+        }
+        .build());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+pub fn callbacks<'a>(
+    url: &str,
+    cached: Option<&'a Credentials>,
+    helper: &'a CredentialHelper,
+    ssh: &SshOptions,
+) -> RemoteCallbacks<'a> {
+    let mut cb = RemoteCallbacks::new();
+
+    let mut tried_agent = false;
+    let mut tried_cached = false;
+    let mut tried_helper = false;
+    let mut key_files = ssh.key_files().into_iter();
+
+    cb.credentials(move |_, username_from_url, allowed| {
+        let username = username_from_url.unwrap_or("git");
+
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username);
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if !tried_agent {
+                tried_agent = true;
+                if let Ok(cred) = Cred::ssh_key_from_agent(username) {
+                    return Ok(cred);
+                }
+            }
+
+            if let Some(key) = key_files.next() {
+                let passphrase = if key_is_encrypted(&key) {
+                    Some(
+                        Password::with_theme(&ColorfulTheme::default())
+                            .with_prompt(format!("Passphrase for {}", key.display()))
+                            .interact()
+                            .map_err(|err| GitError::from_str(&err.to_string()))?,
+                    )
+                } else {
+                    None
+                };
+
+                return Cred::ssh_key(username, None, &key, passphrase.as_deref());
+            }
+
+            return Err(GitError::from_str(
+                "ssh authentication failed, tried ssh-agent and key files",
+            ));
+        }
+
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if !tried_cached {
+                tried_cached = true;
+                if let Some(Credentials { username, password }) = cached {
+                    return Cred::userpass_plaintext(username, password);
+                }
+            }
+
+            if !tried_helper {
+                tried_helper = true;
+                let Credentials { username, password } = helper
+                    .fill()
+                    .map_err(|err| GitError::from_str(&err.to_string()))?;
+
+                return Cred::userpass_plaintext(&username, &password);
+            }
+
+            helper.reject();
+        }
+
+        Err(GitError::from_str("no supported credentials for remote"))
+    });
+
+    let known_hosts = ssh.known_hosts;
+    let port = Url::parse(url)
+        .ok()
+        .and_then(|url| url.port())
+        .unwrap_or(22);
+
+    cb.certificate_check(move |cert, host| {
+        let Some(hostkey) = cert.as_hostkey() else {
+            return Ok(CertificateCheckStatus::CertificatePassthrough);
+        };
+
+        if known_hosts == KnownHosts::Off {
+            return Ok(CertificateCheckStatus::CertificateOk);
+        }
+
+        let (Some(key), Some(key_type)) = (hostkey.hostkey(), hostkey.hostkey_type()) else {
+            return Err(GitError::from_str("remote did not send a host key"));
+        };
+
+        let host = if port == 22 {
+            host.to_string()
+        } else {
+            format!("[{host}]:{port}")
+        };
+
+        match check_known_host(&host, key_type.name(), key) {
+            HostStatus::Match => Ok(CertificateCheckStatus::CertificateOk),
+            HostStatus::Mismatch => Err(GitError::from_str(&format!(
+                "host key for {host} does not match known_hosts"
+            ))),
+            HostStatus::Unknown if known_hosts == KnownHosts::AcceptNew => {
+                add_known_host(&host, key_type.name(), key)
+                    .map_err(|err| GitError::from_str(&err.to_string()))?;
+                println!(
+                    "{}",
+                    format!("Added {host} ({}) to known_hosts", key_type.short_name())
+                        .bright_yellow()
+                );
+
+                Ok(CertificateCheckStatus::CertificateOk)
+            }
+            HostStatus::Unknown => Err(GitError::from_str(&format!(
+                "{host} is not in known_hosts (set pm.knownHosts to accept-new to trust it)"
+            ))),
+        }
+    });
+
+    cb
+}
+
+pub fn is_ssh(remote: &str) -> bool {
+    split_scp(remote).is_some() || remote.starts_with("ssh://")
+}
+
+pub fn remote_host(remote: &str) -> Result<String> {
+    if let Some((host, _)) = split_scp(remote) {
+        return Ok(host
+            .rsplit_once('@')
+            .map_or(host, |(_, host)| host)
+            .to_string());
+    }
+
+    // a local bare repository, e.g. on a usb drive
+    if Path::new(remote).is_absolute() {
+        return Ok(String::new());
+    }
+
+    let url = Url::parse(remote)?;
+    if matches!(url.scheme(), "file" | "dir") {
+        return Ok(String::new());
+    }
+
+    Ok(url.host().context(HostErr)?.to_string())
+}
+
+fn split_scp(remote: &str) -> Option<(&str, &str)> {
+    if remote.contains("://") {
+        return None;
+    }
+
+    remote
+        .split_once(':')
+        .filter(|(host, _)| host.len() > 1 && !host.contains('/'))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum HostStatus {
+    Match,
+    Mismatch,
+    Unknown,
+}
+
+fn known_hosts_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".ssh").join("known_hosts"))
+}
+
+fn check_known_host(host: &str, key_type: &str, key: &[u8]) -> HostStatus {
+    known_hosts_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .map_or(HostStatus::Unknown, |contents| {
+            match_known_hosts(&contents, host, key_type, key)
+        })
+}
+
+fn match_known_hosts(contents: &str, host: &str, key_type: &str, key: &[u8]) -> HostStatus {
+    let mut status = HostStatus::Unknown;
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with("@cert-authority") {
+            continue;
+        }
+
+        let (revoked, line) = line
+            .strip_prefix("@revoked")
+            .map_or((false, line), |rest| (true, rest.trim_start()));
+
+        let mut fields = line.split_whitespace();
+        let (Some(patterns), Some(entry_type), Some(entry_key)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+
+        if !patterns
+            .split(',')
+            .any(|pattern| host_matches(pattern, host))
+        {
+            continue;
+        }
+
+        let same_key = entry_type == key_type
+            && Base64::decode_vec(entry_key).is_ok_and(|entry_key| entry_key == key);
+
+        if revoked && same_key {
+            return HostStatus::Mismatch;
+        }
+
+        if same_key {
+            status = HostStatus::Match;
+        } else if entry_type == key_type && status == HostStatus::Unknown {
+            status = HostStatus::Mismatch;
+        }
+    }
+
+    status
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let Some(hashed) = pattern.strip_prefix("|1|") else {
+        return pattern == host;
+    };
+
+    let Some((salt, hash)) = hashed.split_once('|') else {
+        return false;
+    };
+
+    let (Ok(salt), Ok(hash)) = (Base64::decode_vec(salt), Base64::decode_vec(hash)) else {
+        return false;
+    };
+
+    let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(&salt) else {
+        return false;
+    };
+    mac.update(host.as_bytes());
+
+    mac.verify_slice(&hash).is_ok()
+}
+
+fn add_known_host(host: &str, key_type: &str, key: &[u8]) -> std::io::Result<()> {
+    let Some(path) = known_hosts_path() else {
+        return Ok(());
+    };
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{host} {key_type} {}", Base64::encode_string(key))
+}
+
+fn key_is_encrypted(path: &Path) -> bool {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return false;
+    };
+
+    if contents.contains("ENCRYPTED") {
+        return true;
+    }
+
+    let body = contents
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect::<String>();
+
+    let Ok(decoded) = Base64::decode_vec(&body) else {
+        return false;
+    };
+
+    let Some(rest) = decoded.strip_prefix(b"openssh-key-v1\0") else {
+        return false;
+    };
+
+    rest.get(4..8).is_some_and(|cipher| cipher != b"none")
+}
+
+#[cfg(test)]
+mod tests {
+    use base64ct::{Base64, Encoding};
+    use hmac::{Hmac, Mac};
+    use sha1::Sha1;
+
+    use super::{match_known_hosts, HostStatus};
+
+    const KEY: &[u8] = b"host key";
+    const OTHER_KEY: &[u8] = b"other key";
+
+    fn entry(patterns: &str, key: &[u8]) -> String {
+        format!("{patterns} ssh-ed25519 {}\n", Base64::encode_string(key))
+    }
+
+    fn hashed(host: &str) -> String {
+        let salt = [3u8; 20];
+        let mut mac = Hmac::<Sha1>::new_from_slice(&salt).unwrap();
+        mac.update(host.as_bytes());
+
+        format!(
+            "|1|{}|{}",
+            Base64::encode_string(&salt),
+            Base64::encode_string(&mac.finalize().into_bytes())
+        )
+    }
+
+    fn check(contents: &str, host: &str) -> HostStatus {
+        match_known_hosts(contents, host, "ssh-ed25519", KEY)
+    }
+
+    #[test]
+    fn plain_hosts() {
+        let contents = entry("github.com,140.82.121.4", KEY);
+
+        assert_eq!(check(&contents, "github.com"), HostStatus::Match);
+        assert_eq!(check(&contents, "140.82.121.4"), HostStatus::Match);
+        assert_eq!(check(&contents, "gitlab.com"), HostStatus::Unknown);
+        assert_eq!(
+            check(&entry("github.com", OTHER_KEY), "github.com"),
+            HostStatus::Mismatch
+        );
+    }
+
+    #[test]
+    fn other_key_types_are_unknown() {
+        let contents = format!("github.com ssh-rsa {}\n", Base64::encode_string(OTHER_KEY));
+
+        assert_eq!(check(&contents, "github.com"), HostStatus::Unknown);
+    }
+
+    #[test]
+    fn hashed_hosts() {
+        let contents = entry(&hashed("github.com"), KEY);
+
+        assert_eq!(check(&contents, "github.com"), HostStatus::Match);
+        assert_eq!(check(&contents, "gitlab.com"), HostStatus::Unknown);
+    }
+
+    #[test]
+    fn ports() {
+        let contents = entry("[git.example.com]:2222", KEY);
+
+        assert_eq!(
+            check(&contents, "[git.example.com]:2222"),
+            HostStatus::Match
+        );
+        assert_eq!(check(&contents, "git.example.com"), HostStatus::Unknown);
+    }
+
+    #[test]
+    fn any_matching_key_wins_unless_revoked() {
+        let contents = [
+            "# comment\n".to_string(),
+            entry("github.com", OTHER_KEY),
+            entry("github.com", KEY),
+        ]
+        .concat();
+        assert_eq!(check(&contents, "github.com"), HostStatus::Match);
+
+        let revoked = format!("@revoked {}{contents}", entry("github.com", KEY));
+        assert_eq!(check(&revoked, "github.com"), HostStatus::Mismatch);
+    }
+}
@@ -0,0 +1,703 @@
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use git2::{
+    build::RepoBuilder, Direction, FetchOptions, Oid, PushOptions, Remote, Repository,
+    RepositoryInitOptions, Signature, Tree, TreeWalkMode, TreeWalkResult,
+};
+use snafu::ResultExt;
+use url::Url;
+
+use crate::{
+    auth::{callbacks, CredentialHelper, SshOptions},
+    config::Config,
+    error::{FsErr, HttpErr, PassManagerErr, RemoteLockedErr, Result, SyncConflictErr},
+    layout::to_hex,
+    lock::{Access, DataLock},
+    manager::{Manager, ORIGIN},
+    message::CommitMessage,
+    s3::S3Store,
+    user::Credentials,
+    webdav::WebDavStore,
+};
+
+/// Transport moving the store between devices.
+pub trait SyncBackend {
+    /// Whether the remote holds a store already.
+    fn has_data(&self) -> Result<bool>;
+
+    /// Creates a repository at `data_dir` holding the remote store. It has
+    /// no commits when the remote carries no history.
+    fn clone_into(&self, data_dir: &Path) -> Result<Repository>;
+
+    /// Leaves the remote state at `REMOTE_MAIN_REF` for `merge_remote`.
+    fn fetch(&self, manager: &mut Manager) -> Result<()>;
+
+    /// Sends HEAD. Fails if the remote changed since the last fetch, unless
+    /// `force` is set.
+    fn push(&self, manager: &mut Manager, force: bool) -> Result<()>;
+}
+
+/// Picks the backend by the scheme of the remote url:
+/// `dir:///path` for a shared folder, `webdav://` or `webdavs://` for a
+/// `WebDAV` server, `s3://` or `s3+http://host/bucket/prefix` for an
+/// S3-compatible bucket and git for anything else.
+pub fn backend(name: &str, url: &str, config: &Config) -> Result<Box<dyn SyncBackend>> {
+    let scheme = Url::parse(url)
+        .map(|parsed| parsed.scheme().to_string())
+        .unwrap_or_default();
+    let name = name.to_string();
+
+    Ok(match scheme.as_str() {
+        "dir" => Box::new(Bundled {
+            name,
+            store: DirStore::new(url)?,
+        }),
+        "webdav" | "webdavs" => Box::new(Bundled {
+            name,
+            store: WebDavStore::new(url)?,
+        }),
+        "s3" | "s3+http" => Box::new(Bundled {
+            name,
+            store: S3Store::new(url)?,
+        }),
+        _ => Box::new(GitBackend {
+            name,
+            url: url.to_string(),
+            ssh: SshOptions::from_config(config),
+        }),
+    })
+}
+
+/// Ref holding the last known state of `main` on the remote `name`.
+pub fn remote_ref(name: &str) -> String {
+    format!("refs/remotes/{name}/main")
+}
+
+impl Manager {
+    /// Names and urls of the configured remotes, origin first.
+    pub fn remotes(&self) -> Result<Vec<(String, String)>> {
+        let Some(repo) = &self.repo else {
+            return Ok(Vec::new());
+        };
+
+        let mut remotes = repo
+            .remotes()?
+            .iter()
+            .flatten()
+            .filter_map(|name| {
+                let remote = repo.find_remote(name).ok()?;
+                Some((name.to_string(), remote.url()?.to_string()))
+            })
+            .collect::<Vec<_>>();
+        remotes.sort_by_key(|(name, _)| name != ORIGIN);
+
+        Ok(remotes)
+    }
+
+    fn remote_backend(&self, name: &str) -> Result<Box<dyn SyncBackend>> {
+        let url = self
+            .repo()?
+            .find_remote(name)?
+            .url()
+            .map(ToString::to_string)
+            .ok_or_else(|| git2::Error::from_str("remote url is not valid utf-8"))?;
+
+        backend(name, &url, &self.config)
+    }
+
+    pub fn fetch_from(&mut self, name: &str) -> Result<()> {
+        self.remote_backend(name)?.fetch(self)
+    }
+
+    pub fn push_to(&mut self, name: &str, force: bool) -> Result<()> {
+        self.remote_backend(name)?.push(self, force)
+    }
+
+    pub fn fetch(&mut self) -> Result<()> {
+        if self.user.remote.is_none() {
+            return Ok(());
+        }
+
+        self.fetch_from(ORIGIN)
+    }
+
+    pub fn push(&mut self, force: bool) -> Result<()> {
+        if self.user.remote.is_none() {
+            return Ok(());
+        }
+
+        self.push_to(ORIGIN, force)
+    }
+}
+
+pub struct GitBackend {
+    name: String,
+    url: String,
+    ssh: SshOptions,
+}
+
+impl GitBackend {
+    /// Credentials cached in the vault, only origin has them.
+    fn creds<'a>(&self, manager: &'a Manager) -> Option<&'a Credentials> {
+        manager
+            .user
+            .remote
+            .as_ref()
+            .filter(|_| self.name == ORIGIN)
+            .and_then(|remote| remote.creds.as_ref())
+    }
+}
+
+impl SyncBackend for GitBackend {
+    fn has_data(&self) -> Result<bool> {
+        let helper = CredentialHelper::new(&self.url);
+        let cb = callbacks(&self.url, None, &helper, &self.ssh);
+
+        let mut remote = Remote::create_detached(self.url.as_bytes())?;
+        helper.settle(remote.connect_auth(Direction::Fetch, Some(cb), None))?;
+
+        Ok(remote.default_branch().is_ok())
+    }
+
+    fn clone_into(&self, data_dir: &Path) -> Result<Repository> {
+        let helper = CredentialHelper::new(&self.url);
+
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks(&self.url, None, &helper, &self.ssh));
+
+        let result = RepoBuilder::new()
+            .fetch_options(fetch_options)
+            .clone(&self.url, data_dir);
+
+        helper.settle(result)
+    }
+
+    fn fetch(&self, manager: &mut Manager) -> Result<()> {
+        let creds = self.creds(manager);
+        let helper = CredentialHelper::new(&self.url);
+
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks(&self.url, creds, &helper, &self.ssh));
+
+        helper.settle(manager.repo()?.find_remote(&self.name)?.fetch(
+            &[&format!("+refs/heads/main:{}", remote_ref(&self.name))],
+            Some(&mut fetch_options),
+            None,
+        ))
+    }
+
+    fn push(&self, manager: &mut Manager, force: bool) -> Result<()> {
+        let creds = self.creds(manager);
+        let helper = CredentialHelper::new(&self.url);
+
+        let mut remote = manager.repo()?.find_remote(&self.name)?;
+        helper.settle(remote.connect_auth(
+            Direction::Push,
+            Some(callbacks(&self.url, creds, &helper, &self.ssh)),
+            None,
+        ))?;
+
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(callbacks(&self.url, creds, &helper, &self.ssh));
+
+        helper.settle(remote.push(
+            &[if force {
+                "+refs/heads/main:refs/heads/main"
+            } else {
+                "refs/heads/main:refs/heads/main"
+            }],
+            Some(&mut push_options),
+        ))
+    }
+}
+
+/// Remote keeping the store files, without history, as one tar bundle that
+/// is replaced as a whole.
+pub trait BundleStore {
+    /// The bundle and its version, `None` when nothing was pushed yet.
+    fn get(&self) -> Result<Option<(Vec<u8>, String)>>;
+
+    /// Replaces the bundle if the remote version still is `expected`, or
+    /// nothing was pushed yet when `expected` is `None`. `force` skips the
+    /// check. Returns the new version.
+    fn put(&self, bundle: &[u8], expected: Option<&str>, force: bool) -> Result<String>;
+}
+
+/// Syncs through a [`BundleStore`]. A fetched bundle becomes a commit on top
+/// of the last known remote state, so merging works as with git.
+pub struct Bundled<S> {
+    name: String,
+    store: S,
+}
+
+impl<S: BundleStore> SyncBackend for Bundled<S> {
+    fn has_data(&self) -> Result<bool> {
+        Ok(self.store.get()?.is_some())
+    }
+
+    fn clone_into(&self, data_dir: &Path) -> Result<Repository> {
+        let (bundle, version) = self
+            .store
+            .get()?
+            .ok_or_else(|| git2::Error::from_str("remote holds no store to use"))?;
+
+        std::fs::create_dir_all(data_dir).context(FsErr {
+            path: data_dir.display().to_string(),
+        })?;
+
+        for (path, data) in unpack(&bundle)? {
+            let path = data_dir.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).context(FsErr {
+                    path: parent.display().to_string(),
+                })?;
+            }
+
+            std::fs::write(&path, data).context(FsErr {
+                path: path.display().to_string(),
+            })?;
+        }
+
+        let mut init_opts = RepositoryInitOptions::new();
+        init_opts.initial_head("main");
+        let repo = Repository::init_opts(data_dir, &init_opts)?;
+
+        Config::open(data_dir)?.set_sync_version(&self.name, Some(&version))?;
+
+        Ok(repo)
+    }
+
+    fn fetch(&self, manager: &mut Manager) -> Result<()> {
+        let Some((bundle, version)) = self.store.get()? else {
+            return Ok(());
+        };
+
+        let repo = manager.repo()?;
+        let known = repo.refname_to_id(&remote_ref(&self.name)).ok();
+        if known.is_some()
+            && manager.config.sync_version(&self.name).as_deref() == Some(version.as_str())
+        {
+            return Ok(());
+        }
+
+        let tree = repo.find_tree(write_tree(repo, &unpack(&bundle)?)?)?;
+        let parents = known.map(|oid| repo.find_commit(oid)).transpose()?;
+        let signature = Signature::now(&manager.user.name, &manager.user.email)?;
+
+        let oid = repo.commit(
+            None,
+            &signature,
+            &signature,
+            &CommitMessage::new("store", "fetch", Some(&version)).to_string(),
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )?;
+        repo.reference(&remote_ref(&self.name), oid, true, "pm: fetch")?;
+        drop((tree, parents));
+
+        manager.config.set_sync_version(&self.name, Some(&version))
+    }
+
+    fn push(&self, manager: &mut Manager, force: bool) -> Result<()> {
+        let repo = manager.repo()?;
+        let commit = repo.head()?.peel_to_commit()?;
+        let head = commit.id();
+        if !force && repo.refname_to_id(&remote_ref(&self.name)).ok() == Some(head) {
+            return Ok(());
+        }
+        let bundle = pack(repo, &commit.tree()?)?;
+        drop(commit);
+
+        let version = self.store.put(
+            &bundle,
+            manager.config.sync_version(&self.name).as_deref(),
+            force,
+        )?;
+
+        manager
+            .config
+            .set_sync_version(&self.name, Some(&version))?;
+        manager
+            .repo()?
+            .reference(&remote_ref(&self.name), head, true, "pm: push")?;
+
+        Ok(())
+    }
+}
+
+/// Bundle in a shared folder. `VERSION` names the current bundle, it is
+/// only replaced while holding `pm.lock`.
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+const VERSION_FILE: &str = "VERSION";
+const REMOTE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl DirStore {
+    fn new(url: &str) -> Result<Self> {
+        let dir = Url::parse(url)?.path().to_string();
+
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    fn bundle_path(&self, version: &str) -> PathBuf {
+        self.dir.join(format!("store-{version}.tar"))
+    }
+
+    fn version(&self) -> Result<Option<String>> {
+        let path = self.dir.join(VERSION_FILE);
+
+        match std::fs::read_to_string(&path) {
+            Ok(version) => Ok(Some(version.trim().to_string())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(FsErr {
+                path: path.display().to_string(),
+            }),
+        }
+    }
+}
+
+impl BundleStore for DirStore {
+    fn get(&self) -> Result<Option<(Vec<u8>, String)>> {
+        // A concurrent push may remove the bundle between reading the
+        // version and the bundle, the new version is read then.
+        for _ in 0..3 {
+            let Some(version) = self.version()? else {
+                return Ok(None);
+            };
+
+            if let Ok(bundle) = std::fs::read(self.bundle_path(&version)) {
+                return Ok(Some((bundle, version)));
+            }
+        }
+
+        let path = self.dir.join(VERSION_FILE);
+        Err(std::io::Error::from(std::io::ErrorKind::NotFound)).context(FsErr {
+            path: path.display().to_string(),
+        })
+    }
+
+    fn put(&self, bundle: &[u8], expected: Option<&str>, force: bool) -> Result<String> {
+        std::fs::create_dir_all(&self.dir).context(FsErr {
+            path: self.dir.display().to_string(),
+        })?;
+
+        // the lock file stays, the lock goes with the pushing process
+        let _lock = DataLock::acquire(&self.dir, Access::Exclusive, REMOTE_LOCK_TIMEOUT).map_err(
+            |err| match err {
+                PassManagerErr::Locked { holder } => RemoteLockedErr {
+                    path: self.dir.display().to_string(),
+                    holder,
+                }
+                .build(),
+                err => err,
+            },
+        )?;
+
+        (|| {
+            let current = self.version()?;
+            if !force && current.as_deref() != expected {
+                return Err(SyncConflictErr.build());
+            }
+
+            let version = to_hex(&rand::random::<[u8; 8]>());
+            let path = self.bundle_path(&version);
+            std::fs::write(&path, bundle).context(FsErr {
+                path: path.display().to_string(),
+            })?;
+
+            let tmp_path = self.dir.join(format!("{VERSION_FILE}.tmp"));
+            std::fs::write(&tmp_path, &version).context(FsErr {
+                path: tmp_path.display().to_string(),
+            })?;
+            std::fs::rename(&tmp_path, self.dir.join(VERSION_FILE)).context(FsErr {
+                path: tmp_path.display().to_string(),
+            })?;
+
+            if let Some(current) = current {
+                let _ = std::fs::remove_file(self.bundle_path(&current));
+            }
+
+            Ok(version)
+        })()
+    }
+}
+
+/// Sends `request`, any response with a status is returned to the caller.
+pub fn send(request: ureq::Request, body: &[u8]) -> Result<ureq::Response> {
+    match request.send_bytes(body) {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response),
+        Err(ureq::Error::Transport(err)) => Err(HttpErr {
+            message: err.to_string(),
+        }
+        .build()),
+    }
+}
+
+/// Body of a successful response, any other status is an error.
+pub fn read_body(response: ureq::Response) -> Result<Vec<u8>> {
+    if response.status() >= 300 {
+        return Err(HttpErr {
+            message: format!("{} {}", response.status(), response.status_text()),
+        }
+        .build());
+    }
+
+    let mut body = Vec::new();
+    response.into_reader().read_to_end(&mut body)?;
+
+    Ok(body)
+}
+
+/// Tar archive of every file in `tree`.
+fn pack(repo: &Repository, tree: &Tree) -> Result<Vec<u8>> {
+    let mut files = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if let (Some(name), Some(blob)) = (entry.name(), entry.to_object(repo).ok()) {
+            if let Some(blob) = blob.as_blob() {
+                files.push((format!("{root}{name}"), blob.content().to_vec()));
+            }
+        }
+
+        TreeWalkResult::Ok
+    })?;
+
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        builder.append_data(&mut header, path, data.as_slice())?;
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+fn unpack(bundle: &[u8]) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+
+    for entry in tar::Archive::new(bundle).entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+
+        // only plain relative paths, a bundle must not write elsewhere
+        if path.starts_with('/') || path.split('/').any(|part| part == "..") {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.insert(path, data);
+    }
+
+    Ok(files)
+}
+
+fn write_tree(repo: &Repository, files: &BTreeMap<String, Vec<u8>>) -> Result<Oid> {
+    let mut builder = repo.treebuilder(None)?;
+    let mut dirs: BTreeMap<&str, BTreeMap<String, Vec<u8>>> = BTreeMap::new();
+
+    for (path, data) in files {
+        match path.split_once('/') {
+            Some((dir, rest)) => {
+                dirs.entry(dir)
+                    .or_default()
+                    .insert(rest.to_string(), data.clone());
+            }
+            None => {
+                builder.insert(path, repo.blob(data)?, 0o100_644)?;
+            }
+        }
+    }
+
+    for (dir, files) in dirs {
+        builder.insert(dir, write_tree(repo, &files)?, 0o040_000)?;
+    }
+
+    Ok(builder.write()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use aes_gcm::{Aes256Gcm, KeyInit};
+    use git2::{Repository, RepositoryInitOptions};
+
+    use super::{backend, remote_ref, BundleStore, DirStore};
+    use crate::{
+        config::Config,
+        error::PassManagerErr,
+        layout::to_hex,
+        manager::{set_repo, Manager, ORIGIN, STORE_BIN_PATH, USER_BIN_PATH},
+        store::{Item, Store},
+        user::User,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "pm-test-{name}-{}",
+            to_hex(&rand::random::<[u8; 4]>())
+        ))
+    }
+
+    fn cipher() -> Aes256Gcm {
+        Aes256Gcm::new(&[7; 32].into())
+    }
+
+    /// A manager on `data_dir` without prompting for the key, the store key
+    /// is never used by sync.
+    fn open(data_dir: &Path, repo: Repository) -> Manager {
+        let (user_nonce, user, _) = User::open(&data_dir.join(USER_BIN_PATH), &cipher()).unwrap();
+
+        Manager {
+            store: Store::open(&data_dir.join(STORE_BIN_PATH)).unwrap(),
+            name_key: None,
+            log: None,
+            store_aes: cipher(),
+            data_dir: data_dir.to_path_buf(),
+            key_aes: cipher(),
+            repo: Some(repo),
+            user,
+            user_nonce,
+            config: Config::open(data_dir).unwrap(),
+
+            fs_dirty: false,
+            merge_parent: None,
+            commit_message: None,
+            success_message: None,
+            wipe_bak: false,
+            lock: None,
+        }
+    }
+
+    fn init(data_dir: &Path) -> Manager {
+        std::fs::create_dir_all(data_dir).unwrap();
+
+        let user = User::new("test".to_string(), "test@example.com".to_string());
+        user.save(&data_dir.join(USER_BIN_PATH), &cipher(), [0; 12])
+            .unwrap();
+        Store::new(vec![0; 48], [0; 16], [0; 12])
+            .save(&data_dir.join(STORE_BIN_PATH))
+            .unwrap();
+
+        let mut init_opts = RepositoryInitOptions::new();
+        init_opts.initial_head("main");
+        let repo = Repository::init_opts(data_dir, &init_opts).unwrap();
+        set_repo(&repo, &user).unwrap();
+
+        open(data_dir, repo)
+    }
+
+    fn add(manager: &mut Manager, label: &str) {
+        manager
+            .store
+            .items
+            .insert(label.to_string(), Item::new([0; 12], label.into()));
+        manager.commit(&format!("add {label}")).unwrap();
+    }
+
+    fn head(manager: &Manager) -> git2::Oid {
+        manager
+            .repo
+            .as_ref()
+            .unwrap()
+            .head()
+            .unwrap()
+            .target()
+            .unwrap()
+    }
+
+    #[test]
+    fn dir_store_round_trip() {
+        let dir = temp_dir("dir-store");
+        let store = DirStore::new(&format!("dir://{}", dir.display())).unwrap();
+
+        assert!(store.get().unwrap().is_none());
+
+        let first = store.put(b"first", None, false).unwrap();
+        assert_eq!(
+            store.get().unwrap(),
+            Some((b"first".to_vec(), first.clone()))
+        );
+
+        // someone else pushed since, expecting nothing or the old version
+        // conflicts
+        assert!(matches!(
+            store.put(b"second", None, false),
+            Err(PassManagerErr::SyncConflict)
+        ));
+        let second = store.put(b"second", Some(&first), false).unwrap();
+        assert!(matches!(
+            store.put(b"third", Some(&first), false),
+            Err(PassManagerErr::SyncConflict)
+        ));
+        assert_eq!(store.get().unwrap(), Some((b"second".to_vec(), second)));
+
+        let forced = store.put(b"forced", Some(&first), true).unwrap();
+        assert_eq!(store.get().unwrap(), Some((b"forced".to_vec(), forced)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn git_sync_over_file_url() {
+        let remote_dir = temp_dir("remote");
+        let mut init_opts = RepositoryInitOptions::new();
+        init_opts.bare(true).initial_head("main");
+        Repository::init_opts(&remote_dir, &init_opts).unwrap();
+        let url = format!("file://{}", remote_dir.display());
+
+        let a_dir = temp_dir("a");
+        let mut a = init(&a_dir);
+        a.repo.as_ref().unwrap().remote(ORIGIN, &url).unwrap();
+        let git = backend(ORIGIN, &url, &a.config).unwrap();
+
+        assert!(!git.has_data().unwrap());
+        git.push(&mut a, false).unwrap();
+        assert!(git.has_data().unwrap());
+
+        let b_dir = temp_dir("b");
+        let b_repo = git.clone_into(&b_dir).unwrap();
+        assert_eq!(b_repo.head().unwrap().target(), Some(head(&a)));
+        let mut b = open(&b_dir, b_repo);
+        assert!(b.store.items.is_empty());
+
+        add(&mut a, "github");
+        git.push(&mut a, false).unwrap();
+        git.fetch(&mut b).unwrap();
+        assert_eq!(
+            b.repo()
+                .unwrap()
+                .refname_to_id(&remote_ref(ORIGIN))
+                .unwrap(),
+            head(&a)
+        );
+
+        // b did not merge a's commit, its push is rejected unless forced
+        add(&mut b, "gitlab");
+        assert!(git.push(&mut b, false).is_err());
+        git.push(&mut b, true).unwrap();
+        assert_eq!(
+            Repository::open_bare(&remote_dir)
+                .unwrap()
+                .refname_to_id("refs/heads/main")
+                .unwrap(),
+            head(&b)
+        );
+
+        for dir in [remote_dir, a_dir, b_dir] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use aes_gcm::Aes256Gcm;
+use chrono::{DateTime, Local};
+use hashbrown::HashMap;
+use owo_colors::OwoColorize;
+use snafu::ResultExt;
+
+use crate::{
+    atomic::write_atomic,
+    erase::Wiper,
+    error::{CorruptFileErr, FsErr, NoBackupErr, Result},
+    layout::{store_from_files, ITEMS_DIR},
+    lock::{Access, DataLock},
+    manager::{Manager, STORE_BIN_PATH, USER_BIN_PATH},
+    oplog::{OpLog, OPS_DIR},
+    revision::State,
+    store::Store,
+    table::Table,
+    user::User,
+};
+
+const BACKUP_DIR_NAME: &str = "PassManager-backups";
+const BACKUP_PREFIX: &str = "pm-";
+const BACKUP_EXTENSION: &str = ".tar";
+
+/// Backups are kept next to the data dir by default, so they outlive it.
+pub fn default_backup_dir(data_dir: &Path) -> PathBuf {
+    data_dir.with_file_name(BACKUP_DIR_NAME)
+}
+
+impl Manager {
+    pub fn backup_dir(&self) -> PathBuf {
+        self.config
+            .backup_dir()
+            .unwrap_or_else(|| default_backup_dir(&self.data_dir))
+    }
+
+    /// Copies the store files as they are on disk, still encrypted, into a
+    /// new backup and removes the oldest ones beyond the configured count.
+    pub fn backup(&self) -> Result<()> {
+        let count = self.config.backup_count();
+        if count == 0 || !self.data_dir.join(STORE_BIN_PATH).exists() {
+            return Ok(());
+        }
+
+        let mut builder = tar::Builder::new(Vec::new());
+        for path in [STORE_BIN_PATH, USER_BIN_PATH] {
+            builder.append_path_with_name(self.data_dir.join(path), path)?;
+        }
+        for dir in [ITEMS_DIR, OPS_DIR] {
+            if self.data_dir.join(dir).exists() {
+                builder.append_dir_all(dir, self.data_dir.join(dir))?;
+            }
+        }
+        let data = builder.into_inner()?;
+
+        let backup_dir = self.backup_dir();
+        std::fs::create_dir_all(&backup_dir).context(FsErr {
+            path: backup_dir.display().to_string(),
+        })?;
+
+        let name = format!(
+            "{BACKUP_PREFIX}{}{BACKUP_EXTENSION}",
+            Local::now().format("%Y%m%d-%H%M%S%.3f")
+        );
+        write_atomic(&backup_dir.join(name), &data)?;
+
+        for name in backups(&backup_dir)?.iter().skip(count) {
+            let path = backup_dir.join(name);
+            std::fs::remove_file(&path).context(FsErr {
+                path: path.display().to_string(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn list_backups(&self) -> Result<()> {
+        let backup_dir = self.backup_dir();
+        let backups = backups(&backup_dir)?;
+
+        if backups.is_empty() {
+            println!(
+                "{}",
+                format!("No backups in {}", backup_dir.display()).bright_red()
+            );
+            return Ok(());
+        }
+
+        let mut table = Table::new(["Backup".to_string(), "Created".to_string()]);
+        for name in backups {
+            let created = created(&backup_dir.join(&name))?;
+            table.insert([name, created]);
+        }
+
+        println!(
+            "{} {}",
+            "Backups in".bright_yellow(),
+            backup_dir.display().bright_cyan()
+        );
+        table.display()?;
+
+        Ok(())
+    }
+
+    /// Overwrites and removes the backups holding `label`, in their items or
+    /// operation log. Returns how many were removed.
+    pub fn purge_backups(
+        &self,
+        label: &str,
+        ciphers: &mut Vec<Aes256Gcm>,
+        wiper: &mut Wiper,
+    ) -> Result<usize> {
+        let backup_dir = self.backup_dir();
+        let mut purged = 0;
+
+        for name in backups(&backup_dir)? {
+            let path = backup_dir.join(&name);
+            match holds(&path, label, ciphers) {
+                Ok(false) => (),
+                Ok(true) => {
+                    wiper.file(&path);
+                    purged += 1;
+                }
+                Err(err) => println!(
+                    "{}",
+                    format!("Could not read backup {name}, it was kept: {err}").bright_red()
+                ),
+            }
+        }
+
+        Ok(purged)
+    }
+
+    pub fn restore_backup(&mut self, backup: &str) -> Result<()> {
+        let path = resolve(&self.backup_dir(), backup)?;
+        let files = read_backup(&path)?;
+
+        let mut ciphers = vec![self.store_aes.clone()];
+        let (store, cipher) = store_from_files(&files, &mut ciphers)?;
+        let (user_nonce, user) = User::from_bytes(&files[USER_BIN_PATH], &cipher)?;
+
+        let state = State {
+            items: store.decrypt_items(&cipher)?,
+            store,
+            user,
+            user_nonce,
+        };
+
+        // store_from_files only adds a cipher when the backup used another key
+        let name = backup_name(&path);
+        if !self.apply_state(state, ciphers.len() == 1, &name, &created(&path)?)? {
+            return Ok(());
+        }
+
+        self.success_message = Some(format!("Successfully restored store to {name}"));
+
+        Ok(())
+    }
+}
+
+/// Restores a backup when the data dir is gone, the store comes back in
+/// plain mode.
+pub fn restore_into(data_dir: &Path, backup: &str) -> Result<String> {
+    let path = resolve(&default_backup_dir(data_dir), backup)?;
+    let files = read_backup(&path)?;
+    Store::from_bytes(&files[STORE_BIN_PATH])?;
+
+    std::fs::create_dir(data_dir).context(FsErr {
+        path: data_dir.display().to_string(),
+    })?;
+    let _lock = DataLock::acquire(data_dir, Access::Exclusive, std::time::Duration::ZERO)?;
+
+    for (name, data) in &files {
+        let path = data_dir.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context(FsErr {
+                path: parent.display().to_string(),
+            })?;
+        }
+
+        write_atomic(&path, data)?;
+    }
+
+    Ok(format!(
+        "Successfully restored {}, run `pm store mode git` to keep history in git again",
+        backup_name(&path)
+    ))
+}
+
+/// Names of the backups in `dir`, newest first.
+fn backups(dir: &Path) -> Result<Vec<String>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut names = vec![];
+    for entry in std::fs::read_dir(dir).context(FsErr {
+        path: dir.display().to_string(),
+    })? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_EXTENSION) {
+            names.push(name);
+        }
+    }
+
+    names.sort_unstable_by(|a, b| b.cmp(a));
+
+    Ok(names)
+}
+
+/// Finds a backup by path, by name in `dir` or by name without extension.
+fn resolve(dir: &Path, backup: &str) -> Result<PathBuf> {
+    [
+        PathBuf::from(backup),
+        dir.join(backup),
+        dir.join(format!("{backup}{BACKUP_EXTENSION}")),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+    .ok_or_else(|| NoBackupErr { name: backup }.build())
+}
+
+fn backup_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn created(path: &Path) -> Result<String> {
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .context(FsErr {
+            path: path.display().to_string(),
+        })?;
+
+    Ok(DateTime::<Local>::from(modified)
+        .format("%e %b %y %H:%M")
+        .to_string()
+        .trim()
+        .to_string())
+}
+
+/// Reads the store files of a backup keyed by their path in the data dir.
+/// Anything else in the archive is ignored.
+fn read_backup(path: &Path) -> Result<HashMap<String, Vec<u8>>> {
+    let file = std::fs::File::open(path).context(FsErr {
+        path: path.display().to_string(),
+    })?;
+
+    let mut files = HashMap::new();
+    for entry in tar::Archive::new(file).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let name = entry.path()?.to_string_lossy().to_string();
+        if !is_store_file(Path::new(&name)) {
+            continue;
+        }
+
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        files.insert(name, data);
+    }
+
+    for required in [STORE_BIN_PATH, USER_BIN_PATH] {
+        if !files.contains_key(required) {
+            return Err(CorruptFileErr {
+                path: path.display().to_string(),
+            }
+            .build());
+        }
+    }
+
+    Ok(files)
+}
+
+fn holds(path: &Path, label: &str, ciphers: &mut Vec<Aes256Gcm>) -> Result<bool> {
+    let files = read_backup(path)?;
+    let (store, cipher) = store_from_files(&files, ciphers)?;
+    let ops = files
+        .iter()
+        .filter_map(|(path, data)| Some((path.strip_prefix(&format!("{OPS_DIR}/"))?, data)));
+
+    Ok(store.items.contains_key(label) || OpLog::from_files(ops, &cipher)?.mentions(label))
+}
+
+fn is_store_file(path: &Path) -> bool {
+    let components = path.components().collect::<Vec<_>>();
+
+    match components.as_slice() {
+        [Component::Normal(name)] => *name == STORE_BIN_PATH || *name == USER_BIN_PATH,
+        [Component::Normal(dir), Component::Normal(_)] => *dir == ITEMS_DIR || *dir == OPS_DIR,
+        _ => false,
+    }
+}
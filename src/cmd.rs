@@ -1,10 +1,9 @@
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr};
 
 use clap::{Parser, Subcommand, ValueEnum};
 use email_address::EmailAddress;
-use url::Url;
 
-use crate::styles::STYLES;
+use crate::{auth::remote_host, manager::ORIGIN, message::CommitMessage, styles::STYLES};
 
 /// A Cli based Password Manager with remote sync support
 #[derive(Parser)]
@@ -15,39 +14,113 @@ pub struct Cli {
 }
 
 impl Cli {
+    /// Commands that only read the store and can share the data dir lock.
+    pub const fn is_read_only(&self) -> bool {
+        matches!(
+            &self.subcommand,
+            CliSubcommand::List
+                | CliSubcommand::Copy { .. }
+                | CliSubcommand::Show { .. }
+                | CliSubcommand::History { .. }
+                | CliSubcommand::Diff { .. }
+                | CliSubcommand::User(User {
+                    subcommand: UserSubcommand::Get,
+                })
+                | CliSubcommand::Remote(Remote {
+                    subcommand: RemoteSubcommand::List,
+                })
+                | CliSubcommand::Store(Store {
+                    subcommand: StoreSubcommand::Backups
+                        | StoreSubcommand::Gc { dry_run: true }
+                        | StoreSubcommand::Config {
+                            value: None,
+                            unset: false,
+                            ..
+                        },
+                })
+        )
+    }
+
     pub fn to_commit_message(&self) -> String {
         match &self.subcommand {
             CliSubcommand::List
             | CliSubcommand::Initialize
-            | CliSubcommand::History
+            | CliSubcommand::Remote(_)
+            | CliSubcommand::History { .. }
+            | CliSubcommand::Show { .. }
+            | CliSubcommand::Diff { .. }
             | CliSubcommand::Copy { .. }
             | CliSubcommand::User(User {
                 subcommand: UserSubcommand::Get,
             })
             | CliSubcommand::Store(Store {
-                subcommand: StoreSubcommand::Sync { .. } | StoreSubcommand::Nuke { .. },
+                subcommand:
+                    StoreSubcommand::Sync {
+                        dir: SyncDirection::Push,
+                        ..
+                    }
+                    | StoreSubcommand::Nuke { .. }
+                    | StoreSubcommand::Status
+                    | StoreSubcommand::Backups
+                    | StoreSubcommand::Verify { .. }
+                    | StoreSubcommand::RestoreArchive { .. }
+                    | StoreSubcommand::Mode { .. }
+                    | StoreSubcommand::Purge { .. }
+                    | StoreSubcommand::Gc { .. }
+                    | StoreSubcommand::Config { .. },
             }) => String::new(),
 
-            CliSubcommand::Add { ref label, .. } => format!("store add {label}"),
+            CliSubcommand::Store(Store {
+                subcommand:
+                    StoreSubcommand::Sync {
+                        dir: SyncDirection::Pull,
+                        remote,
+                        ..
+                    },
+            }) => CommitMessage::new(
+                "store",
+                "merge",
+                Some(&format!("{}/main", remote.as_deref().unwrap_or(ORIGIN))),
+            )
+            .to_string(),
+
+            CliSubcommand::Add { ref label, .. } => {
+                CommitMessage::new("store", "add", Some(label)).to_string()
+            }
+
+            CliSubcommand::Delete { ref label, .. } => {
+                CommitMessage::new("store", "delete", Some(label)).to_string()
+            }
 
-            CliSubcommand::Delete { ref label } => {
-                format!("store delete {label}")
+            CliSubcommand::Rename { ref label, .. } => {
+                CommitMessage::new("store", "rename", Some(label)).to_string()
             }
 
             CliSubcommand::Undo { id } => {
-                format!(
-                    "any undo{}",
-                    id.as_ref().map_or_else(String::new, |id| format!(" {id}"))
-                )
+                CommitMessage::new("any", "undo", id.as_deref()).to_string()
             }
 
+            CliSubcommand::Redo => CommitMessage::new("any", "redo", None).to_string(),
+
             CliSubcommand::Store(Store {
                 subcommand: StoreSubcommand::Reset,
-            }) => "store reset".to_string(),
+            }) => CommitMessage::new("store", "reset", None).to_string(),
+
+            CliSubcommand::Store(Store {
+                subcommand: StoreSubcommand::Checkout { rev },
+            }) => CommitMessage::new("store", "checkout", Some(rev)).to_string(),
+
+            CliSubcommand::Store(Store {
+                subcommand: StoreSubcommand::Restore { backup },
+            }) => CommitMessage::new("store", "restore", Some(backup)).to_string(),
 
             CliSubcommand::Store(Store {
                 subcommand: StoreSubcommand::Modify,
-            }) => "store modify".to_string(),
+            }) => CommitMessage::new("store", "modify", None).to_string(),
+
+            CliSubcommand::Store(Store {
+                subcommand: StoreSubcommand::Convert { layout },
+            }) => CommitMessage::new("store", "convert", Some(layout.name())).to_string(),
 
             CliSubcommand::User(User {
                 subcommand:
@@ -55,24 +128,21 @@ impl Cli {
                         name,
                         email,
                         remote,
-                        creds_required,
+                        cache_creds,
                     },
             }) => {
                 let fields = [
                     ("name", name),
                     ("email", email),
                     ("remote", remote),
-                    (
-                        "creds_required",
-                        &creds_required.map(|value| value.to_string()),
-                    ),
+                    ("cache_creds", &cache_creds.map(|value| value.to_string())),
                 ]
                 .iter()
                 .filter_map(|(name, el)| el.is_some().then_some(*name))
                 .collect::<Vec<_>>()
                 .join(",");
 
-                format!("user set {fields}")
+                CommitMessage::new("user", "set", Some(&fields)).to_string()
             }
         }
     }
@@ -108,6 +178,20 @@ pub enum CliSubcommand {
     Delete {
         /// label of the item
         label: String,
+
+        /// overwrite the .bak copies of the store that still hold the item
+        #[arg(long, short)]
+        wipe: bool,
+    },
+
+    /// Rename an item in the store
+    #[command(visible_alias = "ren")]
+    Rename {
+        /// label of the item
+        label: String,
+
+        /// new label of the item
+        new_label: String,
     },
 
     /// Copy the current password of an item to the clipboard
@@ -124,18 +208,74 @@ pub enum CliSubcommand {
     /// Initialize the store
     Initialize,
 
+    /// Print the password of an item, optionally as it was at a past revision
+    Show {
+        /// label of the item
+        label: String,
+
+        /// revision id or date (YYYY-MM-DD [HH:MM]) to read the item at
+        #[arg(long)]
+        at: Option<String>,
+    },
+
     /// Check history
     #[command(visible_alias = "log")]
-    History,
+    History {
+        /// only show commits that changed this label
+        label: Option<String>,
+
+        /// only show commits that changed this label
+        #[arg(long = "label", short, conflicts_with = "label")]
+        label_filter: Option<String>,
+
+        /// only show commits made on or after this date (YYYY-MM-DD [HH:MM])
+        #[arg(long)]
+        since: Option<String>,
+
+        /// only show commits made on or before this date (YYYY-MM-DD [HH:MM])
+        #[arg(long)]
+        until: Option<String>,
+
+        /// only show commits with this action (add, delete, set, undo, ...)
+        #[arg(long, short)]
+        action: Option<String>,
+
+        /// only show commits whose author name or email contains this
+        #[arg(long)]
+        author: Option<String>,
+
+        /// show at most this many commits
+        #[arg(short = 'n', long = "max-count")]
+        count: Option<usize>,
+    },
+
+    /// Show label level changes between two revisions
+    Diff {
+        /// older revision (defaults to the parent of the newer revision)
+        rev_a: Option<String>,
+
+        /// newer revision
+        rev_b: Option<String>,
+
+        /// show old and new values of changed items
+        #[arg(long, short)]
+        reveal: bool,
+    },
 
-    /// Undo a step back or to provided id
+    /// Undo the last change, or revert the commit with the provided id
     Undo { id: Option<String> },
 
+    /// Re-apply the last undone change
+    Redo,
+
     /// Subcommands concerning the store
     Store(Store),
 
     /// Subcommands concerning user
     User(User),
+
+    /// Subcommands concerning additional remotes of this device
+    Remote(Remote),
 }
 
 #[derive(Parser)]
@@ -163,21 +303,157 @@ pub enum StoreSubcommand {
         /// force push to remote
         #[arg(long, short)]
         force: bool,
+
+        /// only push to, or pull from, this remote (pull defaults to origin)
+        #[arg(long, short)]
+        remote: Option<String>,
+    },
+
+    /// Restore the store and user data of a past revision as a new commit
+    #[command(visible_alias = "co")]
+    Checkout {
+        /// revision id or date (YYYY-MM-DD [HH:MM]) to restore
+        rev: String,
+    },
+
+    /// Remove an item from every commit in history, or from the undo journal in plain mode
+    Purge {
+        /// label of the item
+        label: String,
+
+        /// keep the current value, re-encrypted, in the latest commit
+        #[arg(long, short)]
+        keep_current: bool,
+    },
+
+    /// Squash history older than the retention policy and run git gc
+    Gc {
+        /// only show what would be squashed
+        #[arg(long, short = 'n')]
+        dry_run: bool,
+    },
+
+    /// Switch between one file for all items, one file per item or an operation log
+    Convert {
+        /// layout to convert the store to
+        #[arg(value_enum)]
+        layout: Layout,
+    },
+
+    /// Keep history in git or only the encrypted files with a local undo journal
+    Mode {
+        /// mode to convert the store to
+        #[arg(value_enum)]
+        mode: Mode,
+    },
+
+    /// Show the sync state of the store
+    #[command(visible_alias = "st")]
+    Status,
+
+    /// Check the store files and git objects for damage
+    #[command(alias = "fsck")]
+    Verify {
+        /// offer to recover damaged files or items from history
+        #[arg(long, short)]
+        repair: bool,
+    },
+
+    /// List the backups taken before every change
+    Backups,
+
+    /// Restore the store from a backup, showing what changes first
+    Restore {
+        /// name or path of the backup
+        backup: String,
     },
 
     /// Remove the store, user data and all git history
-    #[group(multiple = false)]
     Nuke {
         /// sync to upstream before nuking
-        #[arg(long, short)]
+        #[arg(long, short, conflicts_with = "archive")]
         sync: bool,
 
-        /// archive the directory and save it to pm.tar in the current working directory
+        /// archive the directory, encrypted, to pm.tar in the current working directory
         #[arg(long, short)]
         archive: bool,
+
+        /// also overwrite the git object store and temp clone dirs before removing them
+        #[arg(long, short)]
+        wipe_git: bool,
+    },
+
+    /// Re-create the store from an archive written by store nuke --archive
+    RestoreArchive {
+        /// path of the archive
+        #[arg(default_value = "pm.tar")]
+        archive: PathBuf,
+    },
+
+    /// Get or set settings local to this machine
+    Config {
+        /// setting to get or set (lists all settings if omitted)
+        #[arg(value_enum)]
+        key: Option<ConfigKey>,
+
+        /// new value of the setting
+        #[arg(requires = "key")]
+        value: Option<String>,
+
+        /// remove the setting
+        #[arg(long, short, requires = "key", conflicts_with = "value")]
+        unset: bool,
     },
 }
 
+#[derive(ValueEnum, Clone, Copy)]
+pub enum ConfigKey {
+    /// path of the ssh private key used for ssh remotes
+    SshKey,
+
+    /// host key verification for ssh remotes: strict, accept-new or off
+    KnownHosts,
+
+    /// push after every change, merging remote changes first: true or false
+    AutoSync,
+
+    /// days of history kept in full by store gc (default 90)
+    RetainDays,
+
+    /// snapshots kept of older history by store gc: monthly, weekly or none
+    RetainSnapshots,
+
+    /// seconds to wait for another pm process to release the store (default 10)
+    LockTimeout,
+
+    /// directory of the backups taken before every change (default PassManager-backups next to the store)
+    BackupDir,
+
+    /// backups kept in the backup directory, 0 turns them off (default 10)
+    BackupCount,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// every item in a single store file
+    Single,
+
+    /// one encrypted file per item under items/, plus an encrypted index
+    Split,
+
+    /// append-only log of encrypted operations under ops/, replayed on open
+    Log,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// encrypted files only, no history or sync
+    Plain,
+
+    /// history and sync through a git repository
+    Git,
+}
+
 #[derive(ValueEnum, Clone, Copy)]
 pub enum SyncDirection {
     Push,
@@ -210,9 +486,39 @@ pub enum UserSubcommand {
         #[arg(long, short, value_parser = parse_remote, allow_hyphen_values = true)]
         remote: Option<String>,
 
-        /// sets whether remote host needs credential authentication
-        #[arg(long, short)]
-        creds_required: Option<bool>,
+        /// cache remote credentials inside the vault instead of asking git credential helpers
+        #[arg(long, short, alias = "creds-required")]
+        cache_creds: Option<bool>,
+    },
+}
+
+#[derive(Parser)]
+pub struct Remote {
+    #[command(subcommand)]
+    pub subcommand: RemoteSubcommand,
+}
+
+#[derive(Subcommand)]
+pub enum RemoteSubcommand {
+    /// List the remotes with their sync state
+    #[command(visible_alias = "ls")]
+    List,
+
+    /// Add a remote that sync pushes to next to origin
+    Add {
+        /// name of the remote
+        name: String,
+
+        /// url of the remote
+        #[arg(value_parser = parse_remote)]
+        url: String,
+    },
+
+    /// Remove a remote added with remote add
+    #[command(visible_alias = "rm")]
+    Remove {
+        /// name of the remote
+        name: String,
     },
 }
 
@@ -224,7 +530,7 @@ fn parse_email(arg: &str) -> Result<String, String> {
 
 fn parse_remote(arg: &str) -> Result<String, String> {
     if arg != "-" {
-        return Url::parse(arg)
+        return remote_host(arg)
             .map(|_| arg.to_string())
             .map_err(|err| err.to_string());
     }
@@ -0,0 +1,233 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use owo_colors::OwoColorize;
+
+use crate::{cmd::ConfigKey, error::Result, manager::Manager};
+
+pub const CONFIG_PATH: &str = "pm.config";
+const PENDING_PUSH: &str = "pm.pendingPush";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum KnownHosts {
+    Strict,
+    AcceptNew,
+    Off,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Snapshots {
+    Monthly,
+    Weekly,
+    None,
+}
+
+pub struct Config {
+    inner: git2::Config,
+}
+
+impl Config {
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        Ok(Self {
+            inner: git2::Config::open(&data_dir.join(CONFIG_PATH))?,
+        })
+    }
+
+    pub fn get(&self, key: ConfigKey) -> Option<String> {
+        self.inner.get_string(key.name()).ok()
+    }
+
+    pub fn set(&mut self, key: ConfigKey, value: &str) -> Result<()> {
+        self.inner.set_str(key.name(), value)?;
+
+        Ok(())
+    }
+
+    pub fn unset(&mut self, key: ConfigKey) -> Result<()> {
+        if self.get(key).is_some() {
+            self.inner.remove(key.name())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn ssh_key(&self) -> Option<PathBuf> {
+        self.inner.get_path(ConfigKey::SshKey.name()).ok()
+    }
+
+    pub fn known_hosts(&self) -> KnownHosts {
+        match self.get(ConfigKey::KnownHosts).as_deref() {
+            Some("accept-new") => KnownHosts::AcceptNew,
+            Some("off") => KnownHosts::Off,
+            _ => KnownHosts::Strict,
+        }
+    }
+
+    pub fn auto_sync(&self) -> bool {
+        self.inner
+            .get_bool(ConfigKey::AutoSync.name())
+            .unwrap_or(false)
+    }
+
+    pub fn retain_days(&self) -> u32 {
+        self.get(ConfigKey::RetainDays)
+            .and_then(|days| days.parse().ok())
+            .unwrap_or(90)
+    }
+
+    pub fn retain_snapshots(&self) -> Snapshots {
+        match self.get(ConfigKey::RetainSnapshots).as_deref() {
+            Some("weekly") => Snapshots::Weekly,
+            Some("none") => Snapshots::None,
+            _ => Snapshots::Monthly,
+        }
+    }
+
+    pub fn backup_dir(&self) -> Option<PathBuf> {
+        self.inner.get_path(ConfigKey::BackupDir.name()).ok()
+    }
+
+    pub fn backup_count(&self) -> usize {
+        self.get(ConfigKey::BackupCount)
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(10)
+    }
+
+    pub fn lock_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.get(ConfigKey::LockTimeout)
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(10),
+        )
+    }
+
+    pub fn pending_push(&self) -> Option<String> {
+        self.inner.get_string(PENDING_PUSH).ok()
+    }
+
+    pub fn set_pending_push(&mut self, since: Option<&str>) -> Result<()> {
+        match since {
+            Some(since) => self.inner.set_str(PENDING_PUSH, since)?,
+            None if self.pending_push().is_some() => self.inner.remove(PENDING_PUSH)?,
+            None => (),
+        }
+
+        Ok(())
+    }
+
+    /// Version of the bundle on `remote` this store last synced with.
+    pub fn sync_version(&self, remote: &str) -> Option<String> {
+        self.inner.get_string(&sync_version_key(remote)).ok()
+    }
+
+    pub fn set_sync_version(&mut self, remote: &str, version: Option<&str>) -> Result<()> {
+        let key = sync_version_key(remote);
+
+        match version {
+            Some(version) => self.inner.set_str(&key, version)?,
+            None if self.sync_version(remote).is_some() => self.inner.remove(&key)?,
+            None => (),
+        }
+
+        Ok(())
+    }
+}
+
+fn sync_version_key(remote: &str) -> String {
+    format!("remote.{remote}.syncVersion")
+}
+
+impl ConfigKey {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::SshKey => "pm.sshKey",
+            Self::KnownHosts => "pm.knownHosts",
+            Self::AutoSync => "pm.autoSync",
+            Self::RetainDays => "pm.retainDays",
+            Self::RetainSnapshots => "pm.retainSnapshots",
+            Self::LockTimeout => "pm.lockTimeout",
+            Self::BackupDir => "pm.backupDir",
+            Self::BackupCount => "pm.backupCount",
+        }
+    }
+
+    pub fn validate(self, value: &str) -> Result<(), String> {
+        match self {
+            Self::SshKey | Self::BackupDir => Ok(()),
+            Self::KnownHosts => ["strict", "accept-new", "off"]
+                .contains(&value)
+                .then_some(())
+                .ok_or_else(|| "expected one of strict, accept-new, off".to_string()),
+            Self::AutoSync => ["true", "false"]
+                .contains(&value)
+                .then_some(())
+                .ok_or_else(|| "expected true or false".to_string()),
+            Self::RetainDays => value
+                .parse::<u32>()
+                .map(|_| ())
+                .map_err(|_| "expected a number of days".to_string()),
+            Self::RetainSnapshots => ["monthly", "weekly", "none"]
+                .contains(&value)
+                .then_some(())
+                .ok_or_else(|| "expected one of monthly, weekly, none".to_string()),
+            Self::LockTimeout => value
+                .parse::<u64>()
+                .map(|_| ())
+                .map_err(|_| "expected a number of seconds".to_string()),
+            Self::BackupCount => value
+                .parse::<usize>()
+                .map(|_| ())
+                .map_err(|_| "expected a number of backups".to_string()),
+        }
+    }
+}
+
+impl Manager {
+    pub fn configure(
+        &mut self,
+        key: Option<ConfigKey>,
+        value: Option<&String>,
+        unset: bool,
+    ) -> Result<()> {
+        let Some(key) = key else {
+            for &key in ConfigKey::value_variants() {
+                println!(
+                    "{}: {}",
+                    key.name().bright_yellow(),
+                    self.config
+                        .get(key)
+                        .unwrap_or_else(|| "Not set".to_string())
+                        .bright_cyan()
+                );
+            }
+
+            return Ok(());
+        };
+
+        if unset {
+            self.config.unset(key)?;
+            self.success_message = Some(format!("Successfully unset {}", key.name()));
+        } else if let Some(value) = value {
+            if let Err(err) = key.validate(value) {
+                println!("{}", err.bright_red());
+                return Ok(());
+            }
+
+            self.config.set(key, value)?;
+            self.success_message = Some(format!("Successfully set {}", key.name()));
+        } else {
+            println!(
+                "{}",
+                self.config
+                    .get(key)
+                    .unwrap_or_else(|| "Not set".to_string())
+                    .bright_cyan()
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -15,6 +15,14 @@ pub enum Item<T> {
     Deleted(T),
 }
 
+impl<T> Item<T> {
+    pub const fn key(&self) -> &T {
+        match self {
+            Self::Added(k) | Self::Modified(k) | Self::Deleted(k) => k,
+        }
+    }
+}
+
 impl<T: Display> Display for Item<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -70,3 +78,137 @@ where
         deleted,
     }
 }
+
+pub struct Conflict<K, V> {
+    pub key: K,
+    pub local: Option<V>,
+    pub remote: Option<V>,
+}
+
+pub struct Merge<K, V> {
+    pub merged: HashMap<K, V>,
+    pub conflicts: Vec<Conflict<K, V>>,
+}
+
+pub fn merge<K, V>(
+    base: &HashMap<K, V>,
+    local: &HashMap<K, V>,
+    remote: &HashMap<K, V>,
+) -> Merge<K, V>
+where
+    K: Hash + Ord + Clone,
+    V: PartialEq + Clone,
+{
+    let mut merged = HashMap::new();
+    let mut conflicts = Vec::new();
+
+    let keys = base
+        .keys()
+        .chain(local.keys())
+        .chain(remote.keys())
+        .collect::<HashSet<_>>();
+
+    for key in keys {
+        let (base_value, local_value, remote_value) =
+            (base.get(key), local.get(key), remote.get(key));
+
+        let resolved = if local_value == remote_value || remote_value == base_value {
+            local_value
+        } else if local_value == base_value {
+            remote_value
+        } else {
+            conflicts.push(Conflict {
+                key: key.clone(),
+                local: local_value.cloned(),
+                remote: remote_value.cloned(),
+            });
+            continue;
+        };
+
+        if let Some(value) = resolved {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    // keys come out of a hash set, sort them so conflicts are prompted in a stable order
+    conflicts.sort_unstable_by(|lhs, rhs| lhs.key.cmp(&rhs.key));
+
+    Merge { merged, conflicts }
+}
+
+#[cfg(test)]
+mod tests {
+    use hashbrown::HashMap;
+
+    use super::merge;
+
+    fn map(entries: &[(&'static str, u8)]) -> HashMap<&'static str, u8> {
+        entries.iter().copied().collect()
+    }
+
+    fn conflicts(
+        base: &HashMap<&'static str, u8>,
+        local: &HashMap<&'static str, u8>,
+        remote: &HashMap<&'static str, u8>,
+    ) -> Vec<(&'static str, Option<u8>, Option<u8>)> {
+        merge(base, local, remote)
+            .conflicts
+            .into_iter()
+            .map(|conflict| (conflict.key, conflict.local, conflict.remote))
+            .collect()
+    }
+
+    #[test]
+    fn applies_one_sided_changes() {
+        let base = map(&[("kept", 1), ("modified", 1), ("deleted", 1)]);
+        let changed = map(&[("kept", 1), ("modified", 2), ("added", 1)]);
+        let expected = map(&[("kept", 1), ("modified", 2), ("added", 1)]);
+
+        let from_local = merge(&base, &changed, &base);
+        assert!(from_local.conflicts.is_empty());
+        assert_eq!(from_local.merged, expected);
+
+        let from_remote = merge(&base, &base, &changed);
+        assert!(from_remote.conflicts.is_empty());
+        assert_eq!(from_remote.merged, expected);
+    }
+
+    #[test]
+    fn same_change_on_both_sides() {
+        let base = map(&[("modified", 1), ("deleted", 1)]);
+        let changed = map(&[("modified", 2), ("added", 1)]);
+
+        let result = merge(&base, &changed, &changed);
+        assert!(result.conflicts.is_empty());
+        assert_eq!(result.merged, changed);
+    }
+
+    #[test]
+    fn reports_conflicts_in_key_order() {
+        let base = map(&[("b", 1), ("a", 1), ("c", 1)]);
+        let local = map(&[("b", 2), ("a", 2), ("c", 1)]);
+        let remote = map(&[("b", 3), ("c", 2)]);
+
+        assert_eq!(
+            conflicts(&base, &local, &remote),
+            [("a", Some(2), None), ("b", Some(2), Some(3))]
+        );
+        assert_eq!(merge(&base, &local, &remote).merged, map(&[("c", 2)]));
+    }
+
+    #[test]
+    fn unrelated_histories() {
+        let base = HashMap::new();
+        let local = map(&[("same", 1), ("local", 1), ("differs", 1)]);
+        let remote = map(&[("same", 1), ("remote", 1), ("differs", 2)]);
+
+        assert_eq!(
+            conflicts(&base, &local, &remote),
+            [("differs", Some(1), Some(2))]
+        );
+        assert_eq!(
+            merge(&base, &local, &remote).merged,
+            map(&[("same", 1), ("local", 1), ("remote", 1)])
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use owo_colors::OwoColorize;
+use rand::RngCore;
+
+/// Filesystems that write changes to new blocks, an overwrite leaves the
+/// old contents on disk.
+const COPY_ON_WRITE: [&str; 4] = ["btrfs", "zfs", "bcachefs", "apfs"];
+
+/// Best effort secure erase. Files are overwritten with random bytes and
+/// synced before they are unlinked, which doesn't reach old blocks on copy
+/// on write filesystems or past the wear leveling of SSDs. Files that may
+/// survive are collected for the report.
+#[derive(Default)]
+pub struct Wiper {
+    wiped: usize,
+    unsure: Vec<(PathBuf, String)>,
+    mounts: Option<Vec<(PathBuf, String)>>,
+}
+
+impl Wiper {
+    pub fn file(&mut self, path: &Path) {
+        let Ok(metadata) = std::fs::symlink_metadata(path) else {
+            return;
+        };
+
+        if metadata.is_file() {
+            match overwrite(path, metadata.len()) {
+                Ok(()) => match self.filesystem(path) {
+                    Some(fs) if COPY_ON_WRITE.contains(&fs.as_str()) => self.unsure.push((
+                        path.to_path_buf(),
+                        format!("{fs} is copy on write, old blocks are kept"),
+                    )),
+                    Some(_) => self.wiped += 1,
+                    None => self.unsure.push((
+                        path.to_path_buf(),
+                        "filesystem type unknown, overwrite may not reach old blocks".to_string(),
+                    )),
+                },
+                Err(err) => self.unsure.push((path.to_path_buf(), err.to_string())),
+            }
+        }
+
+        if let Err(err) = std::fs::remove_file(path) {
+            self.unsure
+                .push((path.to_path_buf(), format!("couldn't remove it: {err}")));
+        }
+    }
+
+    /// Wipes every file under `dir` and removes it.
+    pub fn dir(&mut self, dir: &Path) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                self.dir(&path);
+            } else {
+                self.file(&path);
+            }
+        }
+
+        if let Err(err) = std::fs::remove_dir(dir) {
+            self.unsure
+                .push((dir.to_path_buf(), format!("couldn't remove it: {err}")));
+        }
+    }
+
+    pub fn report(&self) {
+        println!(
+            "{}",
+            format!("Overwrote {} file(s) before removing them", self.wiped).bright_yellow()
+        );
+
+        if self.unsure.is_empty() {
+            return;
+        }
+
+        println!("{}", "Could not securely wipe:".bright_red());
+        for (path, reason) in &self.unsure {
+            println!(
+                "  {} {}",
+                path.display().bright_cyan(),
+                format!("({reason})").bright_red()
+            );
+        }
+    }
+
+    /// Type of the filesystem holding `path`, from the longest matching
+    /// mount point. Only known on linux and macOS.
+    fn filesystem(&mut self, path: &Path) -> Option<String> {
+        let path = path
+            .parent()
+            .and_then(|dir| dir.canonicalize().ok())
+            .unwrap_or_else(|| path.to_path_buf());
+
+        self.mounts
+            .get_or_insert_with(mounts)
+            .iter()
+            .filter(|(mount_point, _)| path.starts_with(mount_point))
+            .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+            .map(|(_, fs)| fs.clone())
+    }
+}
+
+fn overwrite(path: &Path, len: u64) -> std::io::Result<()> {
+    // git objects are read-only
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    if permissions.readonly() {
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(path, permissions)?;
+    }
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut chunk = vec![0u8; 64 * 1024];
+    let mut left = len;
+
+    while left > 0 {
+        let size = usize::try_from(left.min(chunk.len() as u64)).unwrap_or(chunk.len());
+        rand::thread_rng().fill_bytes(&mut chunk[..size]);
+        file.write_all(&chunk[..size])?;
+        left -= size as u64;
+    }
+
+    file.sync_all()
+}
+
+#[cfg(target_os = "linux")]
+fn mounts() -> Vec<(PathBuf, String)> {
+    std::fs::read_to_string("/proc/self/mounts")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?.replace("\\040", " ");
+            let fs = fields.next()?;
+
+            Some((PathBuf::from(mount_point), fs.to_string()))
+        })
+        .collect()
+}
+
+// lines like "/dev/disk3s1 on /System/Volumes/Data (apfs, local, journaled)"
+#[cfg(target_os = "macos")]
+fn mounts() -> Vec<(PathBuf, String)> {
+    let Ok(output) = std::process::Command::new("mount").output() else {
+        return Vec::new();
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (_, rest) = line.split_once(" on ")?;
+            let (mount_point, options) = rest.rsplit_once(" (")?;
+            let fs = options.split(',').next()?.trim_end_matches(')');
+
+            Some((PathBuf::from(mount_point), fs.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const fn mounts() -> Vec<(PathBuf, String)> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::Wiper;
+    use crate::layout::to_hex;
+
+    fn wipe_with_mounts(mounts: Vec<(PathBuf, String)>) -> Wiper {
+        let path = std::env::temp_dir().join(format!(
+            "pm-test-wipe-{}",
+            to_hex(&rand::random::<[u8; 4]>())
+        ));
+        std::fs::write(&path, b"secret").unwrap();
+
+        let mut wiper = Wiper {
+            mounts: Some(mounts),
+            ..Wiper::default()
+        };
+        wiper.file(&path);
+        assert!(!path.exists());
+
+        wiper
+    }
+
+    #[test]
+    fn plain_filesystem_is_wiped() {
+        let wiper = wipe_with_mounts(vec![(PathBuf::from("/"), "ext4".to_string())]);
+
+        assert_eq!(wiper.wiped, 1);
+        assert!(wiper.unsure.is_empty());
+    }
+
+    #[test]
+    fn copy_on_write_filesystem_is_unsure() {
+        let wiper = wipe_with_mounts(vec![(PathBuf::from("/"), "apfs".to_string())]);
+
+        assert_eq!(wiper.wiped, 0);
+        assert!(wiper.unsure[0].1.contains("apfs is copy on write"));
+    }
+
+    #[test]
+    fn unknown_filesystem_is_unsure() {
+        let wiper = wipe_with_mounts(vec![]);
+
+        assert_eq!(wiper.wiped, 0);
+        assert!(wiper.unsure[0].1.contains("filesystem type unknown"));
+    }
+
+    #[test]
+    fn longest_mount_point_wins() {
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let wiper = wipe_with_mounts(vec![
+            (PathBuf::from("/"), "btrfs".to_string()),
+            (dir, "ext4".to_string()),
+            (PathBuf::from("/nonexistent"), "zfs".to_string()),
+        ]);
+
+        assert_eq!(wiper.wiped, 1);
+    }
+}
@@ -65,14 +65,61 @@ pub enum PassManagerErr {
     #[snafu(display("credential error: could not find {key} in creds"))]
     Creds { key: String },
 
+    #[snafu(display("git credential {action} failed: {message}"))]
+    CredentialHelper { action: String, message: String },
+
     #[snafu(display("git error: cannot get short id"))]
     InvalidShortId,
 
-    #[snafu(display("invalid commit message '{message}'"))]
-    CommitMsgFormat { message: String },
+    #[snafu(display("could not find {bin} in commit tree"))]
+    MissingBin { bin: String },
+
+    #[snafu(display("incorrect key for incoming store, refusing to merge"))]
+    ForeignKey,
+
+    #[snafu(display("could not decrypt item '{label}', refusing to merge"))]
+    Undecryptable { label: String },
+
+    #[snafu(display("no revision found at '{rev}'"))]
+    NoRevision { rev: String },
+
+    #[snafu(display("invalid date '{date}', expected YYYY-MM-DD [HH:MM]"))]
+    InvalidDate { date: String },
+
+    #[snafu(display("cannot revert {id}, later commits also changed {keys}"))]
+    RevertConflict { id: String, keys: String },
+
+    #[snafu(display("{path} is truncated or corrupt"))]
+    CorruptFile { path: String },
+
+    #[snafu(display("remote changed since the last sync, pull first or push with --force"))]
+    SyncConflict,
+
+    #[snafu(display("http error: {message}"))]
+    Http { message: String },
+
+    #[snafu(display("s3 url names no bucket, use s3://host/<bucket>[/prefix]"))]
+    NoBucket,
+
+    #[snafu(display("store is in plain mode without git history, run `pm store mode git` first"))]
+    PlainMode,
+
+    #[snafu(display("store is locked by {holder}, try again once it finishes"))]
+    Locked { holder: String },
+
+    #[snafu(display("remote {path} is locked by {holder}, try again once it finishes"))]
+    RemoteLocked { path: String, holder: String },
+
+    #[snafu(display("no backup named '{name}', see `pm store backups`"))]
+    NoBackup { name: String },
+
+    #[snafu(display("{path} is corrupt, run `pm store verify --repair` to recover it"))]
+    CorruptStore { path: String },
 
-    #[snafu(display("could not get previous version of {bin} to undo"))]
-    PreviousVersion { bin: String },
+    #[snafu(display(
+        "found {problems} problem(s), `pm store verify --repair` recovers what history still has"
+    ))]
+    Damaged { problems: usize },
 }
 
 pub type Result<T, E = PassManagerErr> = std::result::Result<T, E>;
@@ -0,0 +1,323 @@
+use std::path::{Path, PathBuf};
+
+use aes_gcm::{aead::Aead, Aes256Gcm};
+use chrono::Local;
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use git2::{Repository, RepositoryInitOptions};
+use owo_colors::OwoColorize;
+use rkyv::AlignedVec;
+use snafu::ResultExt;
+
+use crate::{
+    atomic::write_atomic,
+    cmd::{Layout, Mode},
+    erase::Wiper,
+    error::{CorruptFileErr, FsErr, PlainModeErr, Result},
+    manager::{set_repo, Manager, ORIGIN, USER_BIN_PATH},
+    oplog::OpLog,
+    store::{Item, Store},
+    user::User,
+};
+
+pub const JOURNAL_PATH: &str = "journal.bin";
+/// Changes kept for undo in plain mode.
+const JOURNAL_DEPTH: usize = 50;
+
+/// State of the store after a change.
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+#[archive(check_bytes)]
+struct Entry {
+    message: String,
+    time: String,
+    layout: String,
+    /// Serialized store, with every item regardless of the layout.
+    store: Vec<u8>,
+    user: User,
+}
+
+/// Undo journal of a store without git, encrypted with the store key. The
+/// last undo entry is the current state.
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Default)]
+#[archive(check_bytes)]
+struct Journal {
+    undo: Vec<Entry>,
+    redo: Vec<Entry>,
+}
+
+impl Journal {
+    fn open(path: &PathBuf, cipher: &Aes256Gcm) -> Result<Self> {
+        let buf = match std::fs::read(path) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).context(FsErr {
+                    path: path.display().to_string(),
+                })
+            }
+        };
+
+        let corrupt = || {
+            CorruptFileErr {
+                path: path.display().to_string(),
+            }
+            .build()
+        };
+
+        let (nonce, ciphertext) = buf.split_at_checked(12).ok_or_else(corrupt)?;
+        let plaintext = cipher
+            .decrypt(nonce.into(), ciphertext)
+            .map_err(|_| corrupt())?;
+
+        let mut aligned = AlignedVec::with_capacity(plaintext.len());
+        aligned.extend_from_slice(&plaintext);
+
+        rkyv::from_bytes::<Self>(&aligned).map_err(|_| corrupt())
+    }
+
+    fn save(&self, path: &Path, cipher: &Aes256Gcm) -> Result<()> {
+        let data = rkyv::to_bytes::<_, 1024>(self).map_err(|err| err.to_string())?;
+
+        let nonce: [u8; 12] = rand::random();
+        let ciphertext = cipher.encrypt(&nonce.into(), data.as_slice())?;
+
+        write_atomic(path, &[nonce.as_slice(), &ciphertext].concat())
+    }
+}
+
+impl Manager {
+    fn journal_path(&self) -> PathBuf {
+        self.data_dir.join(JOURNAL_PATH)
+    }
+
+    fn journal_entry(&self, message: &str) -> Result<Entry> {
+        Ok(Entry {
+            message: message.lines().next().unwrap_or_default().to_string(),
+            time: Local::now().format("%e %b %y %H:%M").to_string(),
+            layout: self.layout().name().to_string(),
+            store: rkyv::to_bytes::<_, 1024>(&self.store)
+                .map_err(|err| err.to_string())?
+                .to_vec(),
+            user: self.user.clone(),
+        })
+    }
+
+    /// Writes the store files without git and records the change in the
+    /// undo journal.
+    pub fn commit_plain(&mut self, message: &str) -> Result<()> {
+        self.write_files()?;
+
+        let mut journal = Journal::open(&self.journal_path(), &self.store_aes)?;
+        journal.redo.clear();
+        journal.undo.push(self.journal_entry(message)?);
+
+        let excess = journal.undo.len().saturating_sub(JOURNAL_DEPTH);
+        journal.undo.drain(..excess);
+
+        journal.save(&self.journal_path(), &self.store_aes)
+    }
+
+    fn write_files(&mut self) -> Result<()> {
+        self.save_store()?;
+        self.user.save(
+            &self.data_dir.join(USER_BIN_PATH),
+            &self.store_aes,
+            self.user_nonce,
+        )
+    }
+
+    fn restore(&mut self, entry: &Entry) -> Result<()> {
+        self.store = Store::from_bytes(&entry.store)?;
+        self.user = entry.user.clone();
+
+        if entry.layout != self.layout().name() {
+            self.name_key = (entry.layout == Layout::Split.name()).then(rand::random);
+            self.log = (entry.layout == Layout::Log.name()).then(OpLog::default);
+        }
+
+        self.write_files()
+    }
+
+    pub fn undo_plain(&mut self, id: Option<&String>) -> Result<()> {
+        if id.is_some() {
+            return Err(PlainModeErr.build());
+        }
+
+        let mut journal = Journal::open(&self.journal_path(), &self.store_aes)?;
+
+        if journal.undo.len() < 2 {
+            println!("{}", "Nothing to undo".bright_red());
+            return Ok(());
+        }
+
+        let Some(undone) = journal.undo.pop() else {
+            return Ok(());
+        };
+        if let Some(previous) = journal.undo.last() {
+            self.restore(previous)?;
+        }
+
+        self.success_message = Some(format!(
+            "Successfully undid '{}' from {}",
+            undone.message,
+            undone.time.trim()
+        ));
+        journal.redo.push(undone);
+
+        journal.save(&self.journal_path(), &self.store_aes)
+    }
+
+    pub fn redo_plain(&mut self) -> Result<()> {
+        let mut journal = Journal::open(&self.journal_path(), &self.store_aes)?;
+
+        let Some(redone) = journal.redo.pop() else {
+            println!("{}", "Nothing to redo".bright_red());
+            return Ok(());
+        };
+        self.restore(&redone)?;
+
+        self.success_message = Some(format!("Successfully redid '{}'", redone.message));
+        journal.undo.push(redone);
+
+        journal.save(&self.journal_path(), &self.store_aes)
+    }
+
+    /// Changes that can be undone in plain mode.
+    pub fn journal_depth(&self) -> Result<usize> {
+        Ok(Journal::open(&self.journal_path(), &self.store_aes)?
+            .undo
+            .len()
+            .saturating_sub(1))
+    }
+
+    /// Entries of the undo journal holding `label`.
+    pub fn journal_entries_with(&self, label: &str) -> Result<usize> {
+        let journal = Journal::open(&self.journal_path(), &self.store_aes)?;
+
+        let mut count = 0;
+        for entry in journal.undo.iter().chain(&journal.redo) {
+            if Store::from_bytes(&entry.store)?.items.contains_key(label) {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Removes `label` from the store files and every journal entry, the
+    /// latest entry keeps `current`. The old journal is overwritten.
+    pub fn purge_journal(
+        &mut self,
+        label: &str,
+        current: Option<Item>,
+        wiper: &mut Wiper,
+    ) -> Result<()> {
+        let path = self.journal_path();
+        let mut journal = Journal::open(&path, &self.store_aes)?;
+
+        // undo entries come first, the last of them is the current state
+        let latest = journal.undo.len().checked_sub(1);
+        for (i, entry) in journal
+            .undo
+            .iter_mut()
+            .chain(journal.redo.iter_mut())
+            .enumerate()
+        {
+            let mut store = Store::from_bytes(&entry.store)?;
+            store.items.remove(label);
+            if let (true, Some(item)) = (Some(i) == latest, &current) {
+                store.items.insert(label.to_string(), item.clone());
+            }
+
+            entry.store = rkyv::to_bytes::<_, 1024>(&store)
+                .map_err(|err| err.to_string())?
+                .to_vec();
+        }
+
+        if let Some(log) = &mut self.log {
+            log.forget(label);
+        }
+        match current {
+            Some(item) => self.store.items.insert(label.to_string(), item),
+            None => self.store.items.remove(label),
+        };
+        self.write_files()?;
+
+        wiper.file(&path);
+        journal.save(&path, &self.store_aes)
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) -> Result<()> {
+        match (mode, &self.repo) {
+            (Mode::Plain, None) | (Mode::Git, Some(_)) => {
+                println!(
+                    "{}",
+                    format!("Store already is in {} mode", mode.name()).bright_yellow()
+                );
+            }
+
+            (Mode::Plain, Some(_)) => {
+                if !Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(
+                        "This deletes the git history, history and sync stop working. Continue?",
+                    )
+                    .interact()?
+                {
+                    return Ok(());
+                }
+
+                for (name, _) in self.remotes()? {
+                    self.config.set_sync_version(&name, None)?;
+                }
+
+                self.repo = None;
+                let git_dir = self.data_dir.join(".git");
+                std::fs::remove_dir_all(&git_dir).context(FsErr {
+                    path: git_dir.display().to_string(),
+                })?;
+
+                self.config.set_pending_push(None)?;
+
+                let journal = Journal {
+                    undo: vec![self.journal_entry("store mode plain")?],
+                    redo: vec![],
+                };
+                journal.save(&self.journal_path(), &self.store_aes)?;
+
+                self.success_message =
+                    Some("Successfully converted store to plain mode".to_string());
+            }
+
+            (Mode::Git, None) => {
+                let mut init_opts = RepositoryInitOptions::new();
+                init_opts.initial_head("main");
+
+                let repo = Repository::init_opts(&self.data_dir, &init_opts)?;
+                set_repo(&repo, &self.user)?;
+                if let Some(remote) = &self.user.remote {
+                    repo.remote(ORIGIN, &remote.url)?;
+                }
+                self.repo = Some(repo);
+
+                let journal_path = self.journal_path();
+                if journal_path.exists() {
+                    std::fs::remove_file(&journal_path).context(FsErr {
+                        path: journal_path.display().to_string(),
+                    })?;
+                }
+
+                self.success_message = Some("Successfully converted store to git mode".to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Mode {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Plain => "plain",
+            Self::Git => "git",
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use std::{fmt::Write as _, path::Path};
+
+use aes_gcm::{aead::Aead, Aes256Gcm};
+use blake2::{
+    digest::{KeyInit, Mac},
+    Blake2sMac256,
+};
+use git2::{IndexAddOption, Oid, Tree};
+use hashbrown::HashMap;
+use owo_colors::OwoColorize;
+use rkyv::AlignedVec;
+use snafu::ResultExt;
+
+use crate::{
+    atomic::{sync_dir, write_unsynced},
+    cmd::Layout,
+    error::{CorruptFileErr, FsErr, Result},
+    manager::{read_blob, unlock_foreign, Manager, STORE_BIN_PATH, USER_BIN_PATH},
+    oplog::{OpLog, OPS_DIR},
+    store::{Item, Store},
+};
+
+pub const ITEMS_DIR: &str = "items";
+pub const INDEX_NAME: &str = "index.bin";
+
+/// Labels of a split store and the key naming their files. The key is only
+/// stored encrypted, so file names reveal nothing about labels.
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+#[archive(check_bytes)]
+struct ItemIndex {
+    name_key: [u8; 32],
+    labels: Vec<String>,
+}
+
+impl Manager {
+    pub const fn layout(&self) -> Layout {
+        if self.log.is_some() {
+            Layout::Log
+        } else if self.name_key.is_some() {
+            Layout::Split
+        } else {
+            Layout::Single
+        }
+    }
+
+    /// Reads the item files of a split store, or replays the operation log,
+    /// in the data dir into the store.
+    pub fn load_layout(&mut self) -> Result<()> {
+        self.name_key = None;
+        self.log = None;
+
+        let ops_dir = self.data_dir.join(OPS_DIR);
+        if ops_dir.exists() {
+            let log = OpLog::from_dir(&ops_dir, &self.store_aes)?;
+            self.store.items = log.replay().0;
+            self.log = Some(log);
+
+            return Ok(());
+        }
+
+        let items_dir = self.data_dir.join(ITEMS_DIR);
+        if !items_dir.join(INDEX_NAME).exists() {
+            return Ok(());
+        }
+
+        let (name_key, items) = load_items(
+            |name| {
+                let path = items_dir.join(name);
+                std::fs::read(&path).context(FsErr {
+                    path: path.display().to_string(),
+                })
+            },
+            &self.store_aes,
+        )?;
+
+        self.name_key = Some(name_key);
+        self.store.items = items;
+
+        Ok(())
+    }
+
+    /// Writes the store to the data dir in the current layout and removes
+    /// files left over from the other ones. In the log layout, changes
+    /// without a record of their own are appended to the log first.
+    pub fn save_store(&mut self) -> Result<()> {
+        let store_path = self.data_dir.join(STORE_BIN_PATH);
+        let items_dir = self.data_dir.join(ITEMS_DIR);
+        let ops_dir = self.data_dir.join(OPS_DIR);
+
+        let (dir, files) = if let Some(log) = &mut self.log {
+            log.reconcile(&self.store.items, &self.user.to_hashmap(), &self.store_aes)?;
+            remove_dir(&items_dir)?;
+
+            (ops_dir, log.files().clone())
+        } else if let Some(name_key) = &self.name_key {
+            remove_dir(&ops_dir)?;
+
+            (
+                items_dir,
+                split_files(&self.store, name_key, &self.store_aes)?,
+            )
+        } else {
+            self.store.save(&store_path)?;
+            remove_dir(&items_dir)?;
+            remove_dir(&ops_dir)?;
+
+            return Ok(());
+        };
+
+        header(&self.store).save(&store_path)?;
+        write_dir(&dir, &files)
+    }
+
+    /// Stages the store and user files of the current layout.
+    pub fn stage(&self, index: &mut git2::Index) -> Result<()> {
+        index.add_path(Path::new(STORE_BIN_PATH))?;
+        index.add_path(Path::new(USER_BIN_PATH))?;
+
+        index.remove_dir(Path::new(ITEMS_DIR), 0)?;
+        index.remove_dir(Path::new(OPS_DIR), 0)?;
+        match self.layout() {
+            Layout::Single => (),
+            Layout::Split => index.add_all([ITEMS_DIR], IndexAddOption::DEFAULT, None)?,
+            Layout::Log => index.add_all([OPS_DIR], IndexAddOption::DEFAULT, None)?,
+        }
+
+        Ok(())
+    }
+
+    /// Reads the operation log of a commit tree in the log layout.
+    pub fn log_at(&self, tree: &Tree, cipher: &Aes256Gcm) -> Result<Option<OpLog>> {
+        let Some(entry) = tree.get_name(OPS_DIR) else {
+            return Ok(None);
+        };
+
+        let ops_tree = entry.to_object(self.repo()?)?.peel_to_tree()?;
+
+        Ok(Some(OpLog::from_tree(self.repo()?, &ops_tree, cipher)?))
+    }
+
+    /// Reads the store of a commit tree in either layout, unlocking it with
+    /// a known cipher or by asking for its key.
+    pub fn store_at(
+        &self,
+        tree: &Tree,
+        ciphers: &mut Vec<Aes256Gcm>,
+    ) -> Result<(Store, Aes256Gcm)> {
+        let mut store = Store::from_bytes(&read_blob(self.repo()?, tree, STORE_BIN_PATH)?)?;
+        let user_blob = read_blob(self.repo()?, tree, USER_BIN_PATH)?;
+        let cipher = unlock_foreign(&store, &user_blob, ciphers)?;
+
+        if let Some(log) = self.log_at(tree, &cipher)? {
+            store.items = log.replay().0;
+        } else if let Some(entry) = tree.get_name(ITEMS_DIR) {
+            let items_tree = entry.to_object(self.repo()?)?.peel_to_tree()?;
+            let (_, items) =
+                load_items(|name| read_blob(self.repo()?, &items_tree, name), &cipher)?;
+
+            store.items = items;
+        }
+
+        Ok((store, cipher))
+    }
+
+    /// Returns a copy of `tree` holding `store`, written in the layout the
+    /// tree already uses. Records of `purged` are dropped from a log.
+    pub fn store_tree(
+        &self,
+        tree: &Tree,
+        store: &Store,
+        cipher: &Aes256Gcm,
+        purged: Option<&str>,
+    ) -> Result<Oid> {
+        let mut builder = self.repo()?.treebuilder(Some(tree))?;
+
+        if let Some(mut log) = self.log_at(tree, cipher)? {
+            if let Some(label) = purged {
+                log.forget(label);
+            }
+            let fields = log.replay().1;
+            log.reconcile(&store.items, &fields, cipher)?;
+
+            let mut ops_builder = self.repo()?.treebuilder(None)?;
+            for (name, data) in log.files() {
+                ops_builder.insert(name, self.repo()?.blob(data)?, 0o100_644)?;
+            }
+
+            let data = rkyv::to_bytes::<_, 1024>(&header(store)).map_err(|err| err.to_string())?;
+            builder.insert(STORE_BIN_PATH, self.repo()?.blob(&data)?, 0o100_644)?;
+            builder.insert(OPS_DIR, ops_builder.write()?, 0o040_000)?;
+
+            return Ok(builder.write()?);
+        }
+
+        let Some(entry) = tree.get_name(ITEMS_DIR) else {
+            let data = rkyv::to_bytes::<_, 1024>(store).map_err(|err| err.to_string())?;
+            builder.insert(STORE_BIN_PATH, self.repo()?.blob(&data)?, 0o100_644)?;
+
+            return Ok(builder.write()?);
+        };
+
+        let items_tree = entry.to_object(self.repo()?)?.peel_to_tree()?;
+        let index = decrypt_index(&read_blob(self.repo()?, &items_tree, INDEX_NAME)?, cipher)?;
+
+        let mut items_builder = self.repo()?.treebuilder(None)?;
+        for (name, data) in split_files(store, &index.name_key, cipher)? {
+            items_builder.insert(name, self.repo()?.blob(&data)?, 0o100_644)?;
+        }
+
+        let data = rkyv::to_bytes::<_, 1024>(&header(store)).map_err(|err| err.to_string())?;
+        builder.insert(STORE_BIN_PATH, self.repo()?.blob(&data)?, 0o100_644)?;
+        builder.insert(ITEMS_DIR, items_builder.write()?, 0o040_000)?;
+
+        Ok(builder.write()?)
+    }
+
+    pub fn convert(&mut self, layout: Layout) {
+        if self.layout() == layout {
+            println!(
+                "{}",
+                format!("Store already uses the {} layout", layout.name()).bright_yellow()
+            );
+            return;
+        }
+
+        self.name_key = (layout == Layout::Split).then(rand::random);
+        self.log = (layout == Layout::Log).then(OpLog::default);
+
+        self.fs_dirty = true;
+        self.success_message = Some(format!(
+            "Successfully converted store to the {} layout",
+            layout.name()
+        ));
+    }
+}
+
+impl Layout {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Single => "single",
+            Self::Split => "split",
+            Self::Log => "log",
+        }
+    }
+}
+
+/// Labels of a split store with the names of their files.
+pub fn item_files(index: &[u8], cipher: &Aes256Gcm) -> Result<Vec<(String, String)>> {
+    let index = decrypt_index(index, cipher)?;
+
+    Ok(index
+        .labels
+        .into_iter()
+        .map(|label| {
+            let name = item_name(&index.name_key, &label);
+            (label, name)
+        })
+        .collect())
+}
+
+/// Reads the store from files of the data dir keyed by their relative
+/// path, in either layout.
+pub fn store_from_files(
+    files: &HashMap<String, Vec<u8>>,
+    ciphers: &mut Vec<Aes256Gcm>,
+) -> Result<(Store, Aes256Gcm)> {
+    let read = |path: &str| {
+        files.get(path).cloned().ok_or_else(|| {
+            CorruptFileErr {
+                path: path.to_string(),
+            }
+            .build()
+        })
+    };
+    let in_dir = |dir: &str| {
+        let prefix = format!("{dir}/");
+        files
+            .iter()
+            .filter_map(move |(path, data)| Some((path.strip_prefix(&prefix)?, data)))
+            .collect::<Vec<_>>()
+    };
+
+    let mut store = Store::from_bytes(&read(STORE_BIN_PATH)?)?;
+    let cipher = unlock_foreign(&store, &read(USER_BIN_PATH)?, ciphers)?;
+
+    let ops = in_dir(OPS_DIR);
+    if !ops.is_empty() {
+        store.items = OpLog::from_files(ops, &cipher)?.replay().0;
+    } else if files.contains_key(&format!("{ITEMS_DIR}/{INDEX_NAME}")) {
+        let (_, items) = load_items(|name| read(&format!("{ITEMS_DIR}/{name}")), &cipher)?;
+        store.items = items;
+    }
+
+    Ok((store, cipher))
+}
+
+fn remove_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).context(FsErr {
+            path: dir.display().to_string(),
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Makes `dir` hold exactly `files`.
+fn write_dir(dir: &Path, files: &HashMap<String, Vec<u8>>) -> Result<()> {
+    std::fs::create_dir_all(dir).context(FsErr {
+        path: dir.display().to_string(),
+    })?;
+
+    for (name, data) in files {
+        // log records never change, only new files need the fsync
+        let path = dir.join(name);
+        if std::fs::read(&path).is_ok_and(|old| old == *data) {
+            continue;
+        }
+
+        write_unsynced(&path, data)?;
+    }
+
+    for entry in std::fs::read_dir(dir).context(FsErr {
+        path: dir.display().to_string(),
+    })? {
+        let path = entry?.path();
+        let stale = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_none_or(|name| !files.contains_key(name));
+
+        if stale {
+            std::fs::remove_file(&path).context(FsErr {
+                path: path.display().to_string(),
+            })?;
+        }
+    }
+
+    sync_dir(dir)
+}
+
+/// The part of `pm_store.bin` kept in the split and log layouts, everything needed to
+/// unlock the store.
+fn header(store: &Store) -> Store {
+    Store::new(store.key.clone(), store.salt, store.nonce)
+}
+
+fn item_name(name_key: &[u8; 32], label: &str) -> String {
+    to_hex(&keyed_hash(name_key, label.as_bytes()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn keyed_hash(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let mut mac = <Blake2sMac256 as KeyInit>::new(key.into());
+    mac.update(data);
+
+    mac.finalize().into_bytes().into()
+}
+
+/// File names and contents of the items dir. Unchanged items and an
+/// unchanged label set give identical files, so git only sees real changes.
+fn split_files(
+    store: &Store,
+    name_key: &[u8; 32],
+    cipher: &Aes256Gcm,
+) -> Result<HashMap<String, Vec<u8>>> {
+    let mut labels = store.items.keys().cloned().collect::<Vec<_>>();
+    labels.sort();
+
+    let mut files = HashMap::new();
+    for label in &labels {
+        let Item { nonce, password } = &store.items[label];
+        files.insert(
+            item_name(name_key, label),
+            [nonce.as_slice(), password].concat(),
+        );
+    }
+
+    let index = ItemIndex {
+        name_key: *name_key,
+        labels,
+    };
+    files.insert(INDEX_NAME.to_string(), encrypt_index(&index, cipher)?);
+
+    Ok(files)
+}
+
+fn load_items(
+    read: impl Fn(&str) -> Result<Vec<u8>>,
+    cipher: &Aes256Gcm,
+) -> Result<([u8; 32], HashMap<String, Item>)> {
+    let index = decrypt_index(&read(INDEX_NAME)?, cipher)?;
+
+    let mut items = HashMap::new();
+    for label in index.labels {
+        let name = item_name(&index.name_key, &label);
+        let data = read(&name)?;
+        if data.len() < 12 {
+            return Err(CorruptFileErr {
+                path: format!("{ITEMS_DIR}/{name}"),
+            }
+            .build());
+        }
+
+        let (nonce, password) = data.split_at(12);
+        items.insert(label, Item::new(nonce.try_into()?, password.to_vec()));
+    }
+
+    Ok((index.name_key, items))
+}
+
+// The nonce is derived from the contents, re-encrypting the same labels
+// gives the same file and different labels never share a nonce.
+fn encrypt_index(index: &ItemIndex, cipher: &Aes256Gcm) -> Result<Vec<u8>> {
+    let data = rkyv::to_bytes::<_, 256>(index).map_err(|err| err.to_string())?;
+    let hash = keyed_hash(&index.name_key, &data);
+    let nonce: [u8; 12] = hash[..12].try_into()?;
+
+    let ciphertext = cipher.encrypt(&nonce.into(), data.as_slice())?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt_index(data: &[u8], cipher: &Aes256Gcm) -> Result<ItemIndex> {
+    if data.len() < 12 {
+        return Err(CorruptFileErr {
+            path: format!("{ITEMS_DIR}/{INDEX_NAME}"),
+        }
+        .build());
+    }
+
+    let (nonce, ciphertext) = data.split_at(12);
+    let plaintext = cipher.decrypt(nonce.into(), ciphertext)?;
+
+    let mut aligned = AlignedVec::with_capacity(plaintext.len());
+    aligned.extend_from_slice(&plaintext);
+
+    Ok(rkyv::from_bytes::<ItemIndex>(&aligned).map_err(|err| err.to_string())?)
+}
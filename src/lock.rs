@@ -0,0 +1,162 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use owo_colors::OwoColorize;
+use snafu::ResultExt;
+
+use crate::error::{FsErr, LockedErr, Result};
+
+pub const LOCK_PATH: &str = "pm.lock";
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Commands that only read the store, any number can run at once.
+    Shared,
+    Exclusive,
+}
+
+/// Advisory lock on the data dir, released when dropped or when the process
+/// dies. The exclusive holder writes its pid into the lock file so waiting
+/// processes can name it.
+pub struct DataLock {
+    file: File,
+    access: Access,
+}
+
+impl DataLock {
+    pub fn acquire(data_dir: &Path, access: Access, timeout: Duration) -> Result<Self> {
+        let path = data_dir.join(LOCK_PATH);
+        let fs_err = || FsErr {
+            path: path.display().to_string(),
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(fs_err())?;
+
+        let start = Instant::now();
+        let mut waiting = false;
+
+        loop {
+            let result = match access {
+                Access::Shared => file.try_lock_shared(),
+                Access::Exclusive => file.try_lock(),
+            };
+
+            match result {
+                Ok(()) => break,
+                Err(TryLockError::Error(err)) => return Err(err).context(fs_err()),
+                Err(TryLockError::WouldBlock) => (),
+            }
+
+            let holder = holder(&mut file);
+            if start.elapsed() >= timeout {
+                return Err(LockedErr { holder }.build());
+            }
+
+            if !waiting {
+                waiting = true;
+                println!(
+                    "{}",
+                    format!("Waiting for {holder} to release the store...").bright_yellow()
+                );
+            }
+
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        // readers clear the pid too, any pid left while they hold the lock is
+        // from a writer that was killed
+        (|| {
+            file.set_len(0)?;
+            if access == Access::Exclusive {
+                file.rewind()?;
+                write!(file, "{}", std::process::id())?;
+                file.sync_data()?;
+            }
+            Ok(())
+        })()
+        .context(fs_err())?;
+
+        Ok(Self { file, access })
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.access == Access::Shared
+    }
+}
+
+impl Drop for DataLock {
+    // still held here, a killed writer leaves its pid for readers to clear
+    fn drop(&mut self) {
+        if self.access == Access::Exclusive {
+            let _ = self.file.set_len(0);
+        }
+    }
+}
+
+/// Names the process holding the lock by the pid the exclusive holder wrote,
+/// readers leave none.
+fn holder(file: &mut File) -> String {
+    let mut contents = String::new();
+    let pid = file
+        .rewind()
+        .and_then(|()| file.read_to_string(&mut contents))
+        .ok()
+        .and_then(|_| contents.trim().parse::<u32>().ok());
+
+    pid.map_or_else(
+        || "another pm process".to_string(),
+        |pid| format!("pm process {pid}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Access, DataLock};
+    use crate::{error::PassManagerErr, layout::to_hex};
+
+    fn locked_by(dir: &std::path::Path, access: Access) -> String {
+        match DataLock::acquire(dir, access, Duration::ZERO) {
+            Err(PassManagerErr::Locked { holder }) => holder,
+            Err(err) => panic!("unexpected error: {err}"),
+            Ok(_) => panic!("lock was not held"),
+        }
+    }
+
+    #[test]
+    fn names_the_holder() {
+        let dir = std::env::temp_dir().join(format!(
+            "pm-test-lock-{}",
+            to_hex(&rand::random::<[u8; 4]>())
+        ));
+        std::fs::create_dir(&dir).unwrap();
+
+        let writer = DataLock::acquire(&dir, Access::Exclusive, Duration::ZERO).unwrap();
+        let holder = format!("pm process {}", std::process::id());
+        assert_eq!(locked_by(&dir, Access::Exclusive), holder);
+        assert_eq!(locked_by(&dir, Access::Shared), holder);
+        drop(writer);
+
+        // readers share the lock and leave no pid
+        let reader = DataLock::acquire(&dir, Access::Shared, Duration::ZERO).unwrap();
+        let other = DataLock::acquire(&dir, Access::Shared, Duration::ZERO).unwrap();
+        assert_eq!(locked_by(&dir, Access::Exclusive), "another pm process");
+        drop((reader, other));
+
+        DataLock::acquire(&dir, Access::Exclusive, Duration::ZERO).unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
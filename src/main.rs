@@ -1,11 +1,31 @@
+mod archive;
+mod atomic;
+mod auth;
+mod backend;
+mod backup;
 mod cmd;
+mod config;
 mod diff;
+mod erase;
 mod error;
+mod journal;
+mod layout;
+mod lock;
 mod manager;
+mod message;
+mod oplog;
+mod remote;
+mod revision;
+mod rewrite;
+mod s3;
+mod status;
 mod store;
 mod styles;
 mod table;
+mod undo;
 mod user;
+mod verify;
+mod webdav;
 
 use clap::Parser;
 use dialoguer::{theme::ColorfulTheme, Confirm};
@@ -13,11 +33,19 @@ use owo_colors::OwoColorize;
 use snafu::OptionExt;
 
 use crate::{
-    cmd::{Cli, CliSubcommand, Store, StoreSubcommand, User, UserSubcommand},
+    archive::restore_archive,
+    backup::restore_into,
+    cmd::{
+        Cli, CliSubcommand, Remote, RemoteSubcommand, Store, StoreSubcommand, User, UserSubcommand,
+    },
     error::{DataDirErr, Result},
+    lock::Access,
     manager::Manager,
+    revision::HistoryFilter,
+    verify::verify,
 };
 
+#[allow(clippy::too_many_lines)]
 fn run() -> Result<Option<String>> {
     let command = Cli::parse();
 
@@ -30,7 +58,33 @@ fn run() -> Result<Option<String>> {
             return Ok(Some("Store already initialized".to_string()));
         }
 
-        Manager::new(data_dir)?
+        if matches!(
+            command.subcommand,
+            CliSubcommand::Store(Store {
+                subcommand: StoreSubcommand::RestoreArchive { .. }
+            })
+        ) {
+            return Ok(Some(
+                "Store already exists, nuke it before restoring an archive".to_string(),
+            ));
+        }
+
+        // a corrupt store can't be opened, verify reads the files itself
+        if let CliSubcommand::Store(Store {
+            subcommand: StoreSubcommand::Verify { repair },
+        }) = &command.subcommand
+        {
+            return verify(&data_dir, *repair).map(Some);
+        }
+
+        Manager::new(
+            data_dir,
+            if command.is_read_only() {
+                Access::Shared
+            } else {
+                Access::Exclusive
+            },
+        )?
     } else {
         if matches!(command.subcommand, CliSubcommand::Initialize) {
             Manager::init(data_dir)?;
@@ -38,6 +92,18 @@ fn run() -> Result<Option<String>> {
             return Ok(Some("Successfully initialized store".to_string()));
         }
 
+        match &command.subcommand {
+            CliSubcommand::Store(Store {
+                subcommand: StoreSubcommand::Restore { backup },
+            }) => return restore_into(&data_dir, backup).map(Some),
+
+            CliSubcommand::Store(Store {
+                subcommand: StoreSubcommand::RestoreArchive { archive },
+            }) => return restore_archive(&data_dir, archive).map(Some),
+
+            _ => (),
+        }
+
         println!("{}", "Store doesn't exist.".bright_red());
         if Confirm::with_theme(&ColorfulTheme::default())
             .with_prompt("Do you want to initialize store?")
@@ -52,10 +118,12 @@ fn run() -> Result<Option<String>> {
     match &command.subcommand {
         CliSubcommand::Copy { label } => manager.copy(label)?,
 
-        CliSubcommand::Delete { label } => {
-            manager.delete(label);
+        CliSubcommand::Delete { label, wipe } => {
+            manager.delete(label, *wipe);
         }
 
+        CliSubcommand::Rename { label, new_label } => manager.rename(label, new_label)?,
+
         CliSubcommand::List => manager.list()?,
 
         CliSubcommand::Add {
@@ -70,21 +138,77 @@ fn run() -> Result<Option<String>> {
 
         CliSubcommand::Initialize => (),
 
-        CliSubcommand::History => manager.history()?,
+        CliSubcommand::Show { label, at } => manager.show(label, at.as_ref())?,
 
-        CliSubcommand::Undo { id } => manager.undo(id)?,
+        CliSubcommand::History {
+            label,
+            label_filter,
+            since,
+            until,
+            action,
+            author,
+            count,
+        } => manager.history(
+            label.as_ref().or(label_filter.as_ref()),
+            &HistoryFilter::new(
+                since.as_ref(),
+                until.as_ref(),
+                action.as_ref(),
+                author.as_ref(),
+                *count,
+            )?,
+        )?,
 
-        CliSubcommand::Store(Store { subcommand }) => {
-            match subcommand {
-                StoreSubcommand::Reset => manager.reset()?,
+        CliSubcommand::Diff {
+            rev_a,
+            rev_b,
+            reveal,
+        } => manager.diff_revisions(rev_a.as_ref(), rev_b.as_ref(), *reveal)?,
 
-                StoreSubcommand::Modify => manager.modify()?,
+        CliSubcommand::Undo { id } => manager.undo(id.as_ref())?,
 
-                StoreSubcommand::Sync { dir, force } => manager.sync(*dir, *force)?,
+        CliSubcommand::Redo => manager.redo()?,
 
-                StoreSubcommand::Nuke { sync, archive } => manager.nuke(*sync, *archive)?,
-            };
-        }
+        CliSubcommand::Store(Store { subcommand }) => match subcommand {
+            StoreSubcommand::Reset => manager.reset()?,
+
+            StoreSubcommand::Modify => manager.modify()?,
+
+            StoreSubcommand::Sync { dir, force, remote } => {
+                manager.sync(*dir, *force, remote.as_ref())?;
+            }
+
+            StoreSubcommand::Checkout { rev } => manager.checkout(rev)?,
+
+            StoreSubcommand::Convert { layout } => manager.convert(*layout),
+
+            StoreSubcommand::Mode { mode } => manager.set_mode(*mode)?,
+
+            StoreSubcommand::Status => manager.status()?,
+
+            StoreSubcommand::Verify { .. } | StoreSubcommand::RestoreArchive { .. } => (),
+
+            StoreSubcommand::Backups => manager.list_backups()?,
+
+            StoreSubcommand::Restore { backup } => manager.restore_backup(backup)?,
+
+            StoreSubcommand::Gc { dry_run } => manager.compact(*dry_run)?,
+
+            StoreSubcommand::Purge {
+                label,
+                keep_current,
+            } => manager.purge(label, *keep_current)?,
+
+            StoreSubcommand::Nuke {
+                sync,
+                archive,
+                wipe_git,
+            } => manager.nuke(*sync, *archive, *wipe_git)?,
+
+            StoreSubcommand::Config { key, value, unset } => {
+                manager.configure(*key, value.as_ref(), *unset)?;
+            }
+        },
 
         CliSubcommand::User(User { subcommand }) => match subcommand {
             UserSubcommand::Get => manager.get_user(),
@@ -93,8 +217,16 @@ fn run() -> Result<Option<String>> {
                 name,
                 email,
                 remote,
-                creds_required,
-            } => manager.set_user(name, email, remote, *creds_required)?,
+                cache_creds,
+            } => manager.set_user(name.as_ref(), email.as_ref(), remote.as_ref(), *cache_creds)?,
+        },
+
+        CliSubcommand::Remote(Remote { subcommand }) => match subcommand {
+            RemoteSubcommand::List => manager.list_remotes()?,
+
+            RemoteSubcommand::Add { name, url } => manager.add_remote(name, url)?,
+
+            RemoteSubcommand::Remove { name } => manager.remove_remote(name)?,
         },
     }
 
@@ -1,6 +1,7 @@
 use std::{
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 
 use aes_gcm::{
@@ -12,42 +13,61 @@ use chrono::{FixedOffset, NaiveDateTime};
 use clipboard::{ClipboardContext, ClipboardProvider};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Password};
 use email_address::EmailAddress;
-use git2::{
-    Config, Cred, Direction, Oid, Remote, RemoteCallbacks, Repository, RepositoryInitOptions,
-    Signature,
-};
+use git2::{IndexAddOption, Oid, Repository, RepositoryInitOptions, Signature, Time, Tree};
 use hashbrown::hash_map::Entry;
 use owo_colors::OwoColorize;
 use rand::seq::SliceRandom;
 use snafu::{OptionExt, ResultExt};
-use url::Url;
 
 use crate::{
+    auth::remote_host,
+    backend::backend,
+    config::Config,
+    erase::Wiper,
     error::{
-        ChronoErr, CommitMsgFormatErr, FsErr, HostErr, InvalidCommitMessageUtf8Err,
-        InvalidShortIdErr, PassManagerErr, PreviousVersionErr, Result,
+        ChronoErr, ForeignKeyErr, FsErr, InvalidCommitMessageUtf8Err, MissingBinErr,
+        PassManagerErr, PlainModeErr, Result,
     },
+    layout::{to_hex, ITEMS_DIR},
+    lock::{Access, DataLock},
+    message::CommitMessage,
+    oplog::{Op, OpLog, OPS_DIR},
+    revision::{short_id, HistoryFilter},
     store::{Item, Store},
     table::Table,
-    user::{get_remote_credentials, User},
+    user::User,
 };
 
 pub const ORIGIN: &str = "origin";
+pub const REMOTE_MAIN_REF: &str = "refs/remotes/origin/main";
 
 pub struct Manager {
-    pub repo: Repository,
+    /// Git repository of the store, `None` in plain mode.
+    pub repo: Option<Repository>,
     pub data_dir: PathBuf,
 
     pub key_aes: Aes256Gcm,
     pub store_aes: Aes256Gcm,
 
     pub store: Store,
+    /// Key naming the item files, set when the store uses the split layout.
+    pub name_key: Option<[u8; 32]>,
+    /// Operation log, set when the store uses the log layout.
+    pub log: Option<OpLog>,
     pub user: User,
+    pub config: Config,
     pub user_nonce: [u8; 12],
 
     pub fs_dirty: bool,
+    pub merge_parent: Option<Oid>,
+    pub commit_message: Option<String>,
 
     pub success_message: Option<String>,
+    /// Overwrite the `.bak` copies of the old files once saved.
+    pub wipe_bak: bool,
+
+    /// Held until the manager is dropped, taken early by nuke.
+    pub lock: Option<DataLock>,
 }
 
 pub fn length_validator(inp: &str) -> Result<(), String> {
@@ -56,45 +76,70 @@ pub fn length_validator(inp: &str) -> Result<(), String> {
         .ok_or_else(|| "Password must be longer than 8".to_string())
 }
 
+/// Clones go to a dir with this prefix next to the data dir first.
+pub const CLONE_DIR_PREFIX: &str = ".pm-clone-";
 pub const STORE_BIN_PATH: &str = "pm_store.bin";
 pub const USER_BIN_PATH: &str = "user.bin";
 
 impl Manager {
-    pub fn new(data_dir: PathBuf) -> Result<Self> {
+    /// Repository of the store, history and sync are unavailable without it.
+    pub fn repo(&self) -> Result<&Repository> {
+        self.repo.as_ref().context(PlainModeErr)
+    }
+
+    pub fn new(data_dir: PathBuf, access: Access) -> Result<Self> {
+        let config = Config::open(&data_dir)?;
+        let lock = DataLock::acquire(&data_dir, access, config.lock_timeout())?;
+
         let store = Store::open(&data_dir.join(STORE_BIN_PATH))?;
         let key = Password::with_theme(&ColorfulTheme::default())
             .with_prompt("Your key")
             .validate_with(|inp: &String| length_validator(inp))
             .interact()?;
 
-        let mut derived_key = [0u8; 32];
-        Argon2::default().hash_password_into(key.as_bytes(), &store.salt, &mut derived_key)?;
-
-        let key_aes = Aes256Gcm::new(derived_key.as_slice().into());
-
-        let key = key_aes.decrypt(&store.nonce.into(), store.key.as_slice())?;
+        let (key_aes, store_aes) = unlock(&store, &key)?;
 
-        let key: [u8; 32] = key.as_slice().try_into()?;
-
-        let store_aes = Aes256Gcm::new(&key.into());
+        let repo = data_dir
+            .join(".git")
+            .exists()
+            .then(|| Repository::open(&data_dir))
+            .transpose()?;
+        let (user_nonce, user, legacy) = User::open(&data_dir.join(USER_BIN_PATH), &store_aes)?;
 
-        let repo = Repository::open(&data_dir)?;
-        let (user_nonce, user) = User::open(&data_dir.join(USER_BIN_PATH), &store_aes)?;
-
-        Ok(Self {
+        let mut manager = Self {
             store,
+            name_key: None,
+            log: None,
             store_aes,
             data_dir,
             key_aes,
             repo,
             user,
             user_nonce,
+            config,
 
             fs_dirty: false,
+            merge_parent: None,
+            commit_message: None,
             success_message: None,
-        })
+            wipe_bak: false,
+            lock: Some(lock),
+        };
+        manager.load_layout()?;
+
+        if legacy && access == Access::Exclusive && manager.user.migrate_creds() {
+            manager.commit(&CommitMessage::new("user", "migrate", Some("creds")).to_string())?;
+            println!(
+                "{}",
+                "Moved remote credentials from the vault to the git credential helper"
+                    .bright_yellow()
+            );
+        }
+
+        Ok(manager)
     }
 
+    #[allow(clippy::too_many_lines)]
     pub fn init(data_dir: PathBuf) -> Result<Self> {
         let user_key = Password::with_theme(&ColorfulTheme::default())
             .with_prompt("Enter new key")
@@ -114,7 +159,7 @@ impl Manager {
 
         let encrypted_key = key_aes.encrypt(nonce, &key[..])?;
 
-        let global_config = Config::open_default()?;
+        let global_config = git2::Config::open_default()?;
 
         let name = Input::with_theme(&ColorfulTheme::default())
             .with_prompt("Enter username")
@@ -130,7 +175,7 @@ impl Manager {
 
         if let Ok(email) = global_config.get_string("user.email") {
             email_input = email_input.default(email);
-        };
+        }
 
         let email = email_input
             .validate_with(|inp: &String| {
@@ -150,9 +195,11 @@ impl Manager {
         std::fs::create_dir(&data_dir).context(FsErr {
             path: data_dir.display().to_string(),
         })?;
+        let lock = DataLock::acquire(&data_dir, Access::Exclusive, Duration::ZERO)?;
         user.save(&data_dir.join(USER_BIN_PATH), &store_aes, user_nonce)?;
         store.save(&data_dir.join(STORE_BIN_PATH))?;
 
+        let config = Config::open(&data_dir)?;
         let mut remote_has_data = false;
 
         let repo = if Confirm::with_theme(&ColorfulTheme::default())
@@ -163,18 +210,9 @@ impl Manager {
                 .with_prompt("Enter remote url")
                 .validate_with(|inp: &String| {
                     (|| {
-                        let url = Url::parse(inp)?;
-                        let (username, password) =
-                            get_remote_credentials(&url.host().context(HostErr)?.to_string())?;
-
-                        let mut cb = RemoteCallbacks::new();
-                        cb.credentials(|_, _, _| Cred::userpass_plaintext(&username, &password));
-
-                        let mut remote = Remote::create_detached(inp.as_bytes())?;
+                        remote_host(inp)?;
 
-                        remote.connect_auth(Direction::Fetch, Some(cb), None)?;
-
-                        remote_has_data = !remote.list()?.is_empty();
+                        remote_has_data = backend(ORIGIN, inp, &config)?.has_data()?;
 
                         Ok::<(), PassManagerErr>(())
                     })()
@@ -182,30 +220,31 @@ impl Manager {
                 })
                 .interact()?;
 
-            let needs_creds = Confirm::with_theme(&ColorfulTheme::default())
-                .with_prompt("Does remote needs credentials")
-                .interact()?;
-
-            user.set_remote(&remote_url, Some(needs_creds))?;
-
-            let repo = if remote_has_data
+            if remote_has_data
                 && Confirm::with_theme(&ColorfulTheme::default())
                     .with_prompt("Remote has previous data. Do you want to use it")
                     .interact()?
             {
-                Repository::clone(&remote_url, &data_dir)?
-            } else {
-                let mut init_opts = RepositoryInitOptions::new();
-                init_opts.initial_head("main");
+                return Self::clone_remote(data_dir, &remote_url, &user_key);
+            }
 
-                Repository::init_opts(&data_dir, &init_opts)?
-            };
+            user.set_remote(&remote_url, None)?;
+            user.save(&data_dir.join(USER_BIN_PATH), &store_aes, user_nonce)?;
+
+            let mut init_opts = RepositoryInitOptions::new();
+            init_opts.initial_head("main");
+
+            let repo = Repository::init_opts(&data_dir, &init_opts)?;
 
             set_repo(&repo, &user)?;
             repo.remote(ORIGIN, &remote_url)?;
 
-            repo
-        } else {
+            Some(repo)
+        } else if Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Do you want to keep history in git")
+            .default(true)
+            .interact()?
+        {
             let mut init_opts = RepositoryInitOptions::new();
             init_opts.initial_head("main");
 
@@ -213,21 +252,109 @@ impl Manager {
 
             set_repo(&repo, &user)?;
 
-            repo
+            Some(repo)
+        } else {
+            None
         };
 
-        Ok(Self {
+        let mut manager = Self {
             store,
+            name_key: None,
+            log: None,
             store_aes,
             data_dir,
             key_aes,
             repo,
             user,
             user_nonce,
+            config,
 
             fs_dirty: false,
+            merge_parent: None,
+            commit_message: None,
             success_message: None,
-        })
+            wipe_bak: false,
+            lock: Some(lock),
+        };
+
+        if manager.repo.is_none() {
+            manager.commit_plain(&CommitMessage::new("store", "initialize", None).to_string())?;
+        }
+
+        Ok(manager)
+    }
+
+    fn clone_remote(data_dir: PathBuf, remote_url: &str, user_key: &str) -> Result<Self> {
+        let backend = backend(ORIGIN, remote_url, &Config::open(&data_dir)?)?;
+
+        // cloned next to the data dir first, a failed clone keeps the new store
+        let clone_dir = data_dir
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join(format!(
+                "{CLONE_DIR_PREFIX}{}",
+                to_hex(&rand::random::<[u8; 4]>())
+            ));
+        if let Err(err) = backend.clone_into(&clone_dir) {
+            let _ = std::fs::remove_dir_all(&clone_dir);
+            return Err(err);
+        }
+
+        std::fs::remove_dir_all(&data_dir).context(FsErr {
+            path: data_dir.display().to_string(),
+        })?;
+        std::fs::rename(&clone_dir, &data_dir).context(FsErr {
+            path: data_dir.display().to_string(),
+        })?;
+
+        let repo = Repository::open(&data_dir)?;
+        let lock = DataLock::acquire(&data_dir, Access::Exclusive, Duration::ZERO)?;
+        let store = Store::open(&data_dir.join(STORE_BIN_PATH))?;
+
+        let (key_aes, store_aes) = if let Ok(ciphers) = unlock(&store, user_key) {
+            ciphers
+        } else {
+            let key = Password::with_theme(&ColorfulTheme::default())
+                .with_prompt("Key of remote store")
+                .interact()?;
+
+            unlock(&store, &key)?
+        };
+
+        let (user_nonce, user, _) = User::open(&data_dir.join(USER_BIN_PATH), &store_aes)?;
+        let config = Config::open(&data_dir)?;
+
+        // bundle remotes carry no history, it starts at the fetched state
+        if repo.head().is_err() {
+            set_repo(&repo, &user)?;
+            repo.remote(ORIGIN, remote_url)?;
+
+            let head = repo.head()?.peel_to_commit()?.id();
+            repo.reference(REMOTE_MAIN_REF, head, true, "pm: clone")?;
+        }
+
+        let mut manager = Self {
+            store,
+            name_key: None,
+            log: None,
+            store_aes,
+            data_dir,
+            key_aes,
+            repo: Some(repo),
+            user,
+            user_nonce,
+            config,
+
+            fs_dirty: false,
+            merge_parent: None,
+            commit_message: None,
+            success_message: None,
+            wipe_bak: false,
+            lock: Some(lock),
+        };
+        manager.load_layout()?;
+
+        Ok(manager)
     }
 }
 
@@ -255,16 +382,11 @@ impl Manager {
             String::from_utf8(password)?
         };
 
-        let nonce_slice: [u8; 12] = rand::random();
-        let aes_nonce = Nonce::from_slice(&nonce_slice);
-
-        let ciphertext = self
-            .store_aes
-            .encrypt(aes_nonce, password.as_bytes().as_ref())?;
+        let item = self.encrypt_item(password.as_bytes())?;
 
         match self.store.items.entry(label.to_string()) {
             Entry::Vacant(entry) => {
-                entry.insert(Item::new(nonce_slice, ciphertext));
+                entry.insert(item);
             }
 
             Entry::Occupied(mut entry) => {
@@ -273,10 +395,10 @@ impl Manager {
                         .with_prompt("Do you want to modify?")
                         .interact()?;
                 if confirmed {
-                    entry.insert(Item::new(nonce_slice, ciphertext));
+                    entry.insert(item);
                 }
             }
-        };
+        }
 
         self.fs_dirty = true;
         self.success_message = Some(format!("Successfully added '{label}' to store"));
@@ -284,15 +406,60 @@ impl Manager {
         Ok(())
     }
 
-    pub fn delete(&mut self, label: &str) {
+    pub fn delete(&mut self, label: &str, wipe: bool) {
         if self.store.items.remove(label).is_none() {
             println!("{}", "No item found in store".bright_red());
         }
 
+        if wipe {
+            self.wipe_bak = true;
+
+            let mut kept = if self.repo.is_some() {
+                format!("'{label}' stays in git history until `pm store purge {label}`")
+            } else {
+                format!("'{label}' stays in the undo journal")
+            };
+            if self.config.backup_count() > 0 {
+                kept.push_str(" and in the backups");
+            }
+            println!("{}", kept.bright_yellow());
+        }
+
         self.fs_dirty = true;
         self.success_message = Some(format!("Successfully deleted '{label}' from store"));
     }
 
+    pub fn rename(&mut self, label: &str, new_label: &str) -> Result<()> {
+        if self.store.items.contains_key(new_label) {
+            println!(
+                "{}",
+                format!("Item '{new_label}' already exists").bright_red()
+            );
+            return Ok(());
+        }
+
+        let Some(item) = self.store.items.remove(label) else {
+            println!("{}", "No item found in store".bright_red());
+            return Ok(());
+        };
+        self.store.items.insert(new_label.to_string(), item);
+
+        if let Some(log) = &mut self.log {
+            log.append(
+                Op::Rename {
+                    from: label.to_string(),
+                    to: new_label.to_string(),
+                },
+                &self.store_aes,
+            )?;
+        }
+
+        self.fs_dirty = true;
+        self.success_message = Some(format!("Successfully renamed '{label}' to '{new_label}'"));
+
+        Ok(())
+    }
+
     pub fn copy(&mut self, label: &str) -> Result<()> {
         let Some(item) = self.store.items.get(label) else {
             println!("No item found in store");
@@ -335,8 +502,18 @@ impl Manager {
         Ok(())
     }
 
-    pub fn history(&self) -> Result<()> {
-        let mut revwalk = self.repo.revwalk()?;
+    pub fn history(&mut self, label: Option<&String>, filter: &HistoryFilter) -> Result<()> {
+        self.repo()?;
+
+        if self.log.is_some() {
+            return self.log_history(label, filter);
+        }
+
+        if let Some(label) = label {
+            return self.label_log(label, filter);
+        }
+
+        let mut revwalk = self.repo()?.revwalk()?;
         revwalk.push_head()?;
 
         let mut table = Table::new([
@@ -346,213 +523,218 @@ impl Manager {
             "Time".to_string(),
             "Id".to_string(),
         ]);
+        let mut shown = 0;
 
         for oid in revwalk {
-            let commit = self.repo.find_commit(oid?)?;
+            if filter.is_full(shown) {
+                break;
+            }
 
-            let commit_message = commit.message().context(InvalidCommitMessageUtf8Err)?;
-            let commit_parts = parse_commit_message(commit_message);
+            let commit = self.repo()?.find_commit(oid?)?;
 
-            let commit_time = commit.time();
-            let time = NaiveDateTime::from_timestamp_opt(commit_time.seconds(), 0)
-                .context(ChronoErr { item: "time" })?;
-            let tz = FixedOffset::east_opt(commit_time.offset_minutes() * 60)
-                .context(ChronoErr { item: "offset" })?;
-            let time = time + tz;
+            let message =
+                CommitMessage::parse(commit.message().context(InvalidCommitMessageUtf8Err)?);
+
+            if !filter.matches(&commit, &message.action) {
+                continue;
+            }
 
             table.insert([
-                commit_parts[0].clone(),
-                commit_parts[1].clone(),
-                commit_parts[2].clone(),
-                time.format("%e %b %y %H:%M").to_string(),
-                commit
-                    .into_object()
-                    .short_id()?
-                    .as_str()
-                    .context(InvalidShortIdErr)?
-                    .to_string(),
+                message.bin.clone(),
+                message.action.clone(),
+                message.value_or_dash(),
+                format_time(commit.time())?,
+                short_id(&commit)?,
             ]);
+            shown += 1;
         }
 
-        table.display()?;
+        if table.is_empty() {
+            println!("{}", "No matching history".bright_red());
+            return Ok(());
+        }
 
-        Ok(())
+        self.page(table)
     }
 
-    pub fn undo(&mut self, id: &Option<String>) -> Result<()> {
-        let commit = id.as_ref().map_or_else(
-            || {
-                self.repo
-                    .head()?
-                    .resolve()?
-                    .peel_to_commit()
-                    .map_err(|_| git2::Error::from_str("Couldn't find commit"))
-            },
-            |id| self.repo.find_commit(Oid::from_str(id)?),
-        )?;
+    /// Pages `table` without holding the data lock, the reader may keep the
+    /// pager open for long.
+    pub fn page<const N: usize>(&mut self, table: Table<N>) -> Result<()> {
+        self.lock = None;
 
-        let message = commit
-            .message()
-            .context(InvalidCommitMessageUtf8Err)?
-            .to_string();
-        let parts = parse_commit_message(&message);
-
-        drop(commit);
-
-        match parts[0].as_str() {
-            "store" => match parts[1].as_str() {
-                "add" => self.delete(&parts[2]),
-                action @ ("delete" | "reset") => {
-                    let parent_commit = id
-                        .as_ref()
-                        .map_or_else(
-                            || {
-                                self.repo
-                                    .head()?
-                                    .resolve()?
-                                    .peel_to_commit()
-                                    .map_err(|_| git2::Error::from_str("Couldn't find commit"))
-                            },
-                            |id| self.repo.find_commit(Oid::from_str(id)?),
-                        )?
-                        .parent(0)?;
-
-                    let tree = parent_commit.tree()?;
-                    let blob = tree
-                        .get_name(STORE_BIN_PATH)
-                        .context(PreviousVersionErr {
-                            bin: STORE_BIN_PATH,
-                        })?
-                        .to_object(&self.repo)?
-                        .into_blob()
-                        .map_err(|_| git2::Error::from_str("Couldn't convert object to blob"))?;
-
-                    let old_store =
-                        rkyv::from_bytes::<Store>(blob.content()).map_err(|err| err.to_string())?;
-
-                    if action == "delete" {
-                        self.store
-                            .items
-                            .insert(parts[2].clone(), old_store.items[&parts[2]].clone());
-                    } else if action == "reset" {
-                        self.store.items = old_store.items;
-                    }
-                }
+        table.page()
+    }
 
-                "modify" => {
-                    println!("{}", "Cannot undo password modication".bright_red());
-                }
-                _ => return Err(CommitMsgFormatErr { message }.build()),
-            },
-
-            "user" => match parts[1].as_str() {
-                "set" => {
-                    let parent_commit = id
-                        .as_ref()
-                        .map_or_else(
-                            || {
-                                self.repo
-                                    .head()?
-                                    .resolve()?
-                                    .peel_to_commit()
-                                    .map_err(|_| git2::Error::from_str("Couldn't find commit"))
-                            },
-                            |id| self.repo.find_commit(Oid::from_str(id)?),
-                        )?
-                        .parent(0)?;
-
-                    let tree = parent_commit.tree()?;
-                    let blob = tree
-                        .get_name(USER_BIN_PATH)
-                        .context(PreviousVersionErr { bin: USER_BIN_PATH })?
-                        .to_object(&self.repo)?
-                        .into_blob()
-                        .map_err(|_| git2::Error::from_str("Couldn't convert object to blob"))?;
-
-                    let (nonce_slice, ciphertext) = blob.content().split_at(12);
-                    let decrypted_buf = self.store_aes.decrypt(nonce_slice.into(), ciphertext)?;
-
-                    let old_user =
-                        rkyv::from_bytes::<User>(&decrypted_buf).map_err(|err| err.to_string())?;
-
-                    let fields = parts[2].split(',').collect::<Vec<_>>();
-
-                    for field in fields {
-                        if field == "name" {
-                            self.user.name = old_user.name.clone();
-                        } else if field == "email" {
-                            self.user.email = old_user.email.clone();
-                        } else if field == "remote" {
-                            self.user.remote = old_user.remote.clone();
-                        }
-                    }
-                }
-                _ => return Err(CommitMsgFormatErr { message }.build()),
-            },
+    pub fn encrypt_item(&self, plaintext: &[u8]) -> Result<Item> {
+        let nonce: [u8; 12] = rand::random();
+        let ciphertext = self.store_aes.encrypt(&nonce.into(), plaintext)?;
 
-            _ => return Err(CommitMsgFormatErr { message }.build()),
-        }
+        Ok(Item::new(nonce, ciphertext))
+    }
 
-        Ok(())
+    pub fn user_at(&self, tree: &Tree) -> Result<([u8; 12], User)> {
+        User::from_bytes(
+            &read_blob(self.repo()?, tree, USER_BIN_PATH)?,
+            &self.store_aes,
+        )
     }
 }
 
 impl Manager {
-    pub fn save(self, message: &str) -> Result<Option<String>> {
+    pub fn save(mut self, message: &str) -> Result<Option<String>> {
         if self.fs_dirty {
-            let mut index = self.repo.index()?;
+            self.backup()?;
 
-            self.store.save(&self.data_dir.join(STORE_BIN_PATH))?;
-            self.user.save(
-                &self.data_dir.join(USER_BIN_PATH),
-                &self.store_aes,
-                self.user_nonce,
-            )?;
+            let message = self
+                .commit_message
+                .take()
+                .unwrap_or_else(|| message.to_string());
+            self.commit(&message)?;
+        }
 
-            index.add_path(Path::new(STORE_BIN_PATH))?;
-            index.add_path(Path::new(USER_BIN_PATH))?;
-
-            let oid = index.write_tree()?;
-            let signature = Signature::now(&self.user.name, &self.user.email)?;
-            let parent_commit = self
-                .repo
-                .head()?
-                .resolve()?
-                .peel_to_commit()
-                .map_err(|_| git2::Error::from_str("Couldn't find commit"))?;
-
-            let tree = self.repo.find_tree(oid)?;
-            self.repo.commit(
-                Some("HEAD"),
-                &signature,
-                &signature,
-                message,
-                &tree,
-                &[&parent_commit],
-            )?;
+        if self.wipe_bak {
+            let mut wiper = Wiper::default();
+            for path in [STORE_BIN_PATH, USER_BIN_PATH] {
+                wiper.file(&self.data_dir.join(format!("{path}.bak")));
+            }
+            wiper.report();
+        }
+
+        if self.config.auto_sync()
+            && self.lock.as_ref().is_some_and(|lock| !lock.is_shared())
+            && self.repo.is_some()
+            && self.user.remote.is_some()
+            && self.data_dir.exists()
+            && (self.fs_dirty || self.config.pending_push().is_some())
+        {
+            self.auto_sync()?;
         }
 
         Ok(self.success_message)
     }
+
+    pub fn commit(&mut self, message: &str) -> Result<()> {
+        if self.repo.is_none() {
+            return self.commit_plain(message);
+        }
+
+        let mut index = self.repo()?.index()?;
+
+        self.save_store()?;
+        self.user.save(
+            &self.data_dir.join(USER_BIN_PATH),
+            &self.store_aes,
+            self.user_nonce,
+        )?;
+
+        self.stage(&mut index)?;
+        index.write()?;
+
+        let oid = index.write_tree()?;
+        let signature = Signature::now(&self.user.name, &self.user.email)?;
+        let parent_commit = self
+            .repo()?
+            .head()?
+            .resolve()?
+            .peel_to_commit()
+            .map_err(|_| git2::Error::from_str("Couldn't find commit"))?;
+
+        let repo = self.repo()?;
+        let merge_commit = self
+            .merge_parent
+            .map(|oid| repo.find_commit(oid))
+            .transpose()?;
+
+        let parents = [Some(&parent_commit), merge_commit.as_ref()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        let tree = self.repo()?.find_tree(oid)?;
+        self.repo()?.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )?;
+
+        Ok(())
+    }
 }
 
-fn parse_commit_message(message: &str) -> Vec<String> {
-    let mut commit_parts = message.split(' ').map(String::from).collect::<Vec<_>>();
+pub fn format_time(time: Time) -> Result<String> {
+    let naive =
+        NaiveDateTime::from_timestamp_opt(time.seconds(), 0).context(ChronoErr { item: "time" })?;
+    let tz =
+        FixedOffset::east_opt(time.offset_minutes() * 60).context(ChronoErr { item: "offset" })?;
+
+    Ok((naive + tz).format("%e %b %y %H:%M").to_string())
+}
+
+pub fn unlock(store: &Store, key: &str) -> Result<(Aes256Gcm, Aes256Gcm)> {
+    let mut derived_key = [0u8; 32];
+    Argon2::default().hash_password_into(key.as_bytes(), &store.salt, &mut derived_key)?;
+
+    let key_aes = Aes256Gcm::new(derived_key.as_slice().into());
+
+    let key = key_aes.decrypt(&store.nonce.into(), store.key.as_slice())?;
+
+    let key: [u8; 32] = key.as_slice().try_into()?;
 
-    if commit_parts.len() == 2 {
-        commit_parts.push("-".to_string());
+    Ok((key_aes, Aes256Gcm::new(&key.into())))
+}
+
+pub fn unlock_foreign(
+    store: &Store,
+    user_blob: &[u8],
+    known: &mut Vec<Aes256Gcm>,
+) -> Result<Aes256Gcm> {
+    if let Some(cipher) = known
+        .iter()
+        .find(|cipher| User::from_bytes(user_blob, cipher).is_ok())
+    {
+        return Ok(cipher.clone());
     }
 
-    commit_parts
+    println!(
+        "{}",
+        "Incoming store is encrypted with a different key".bright_yellow()
+    );
+
+    let key = Password::with_theme(&ColorfulTheme::default())
+        .with_prompt("Key of incoming store")
+        .interact()?;
+
+    let (_, cipher) = unlock(store, &key).map_err(|_| ForeignKeyErr.build())?;
+    User::from_bytes(user_blob, &cipher).map_err(|_| ForeignKeyErr.build())?;
+
+    known.push(cipher.clone());
+
+    Ok(cipher)
+}
+
+pub fn read_blob(repo: &Repository, tree: &Tree, path: &str) -> Result<Vec<u8>> {
+    let blob = tree
+        .get_name(path)
+        .context(MissingBinErr { bin: path })?
+        .to_object(repo)?
+        .into_blob()
+        .map_err(|_| git2::Error::from_str("Couldn't convert object to blob"))?;
+
+    Ok(blob.content().to_vec())
 }
 
-fn set_repo(repo: &Repository, user: &User) -> Result<()> {
+pub fn set_repo(repo: &Repository, user: &User) -> Result<()> {
     repo.add_ignore_rule(&format!("{STORE_BIN_PATH}.bak\n{USER_BIN_PATH}.bak"))?;
 
     let mut index = repo.index()?;
 
     index.add_path(Path::new(STORE_BIN_PATH))?;
     index.add_path(Path::new(USER_BIN_PATH))?;
+    index.add_all([ITEMS_DIR, OPS_DIR], IndexAddOption::DEFAULT, None)?;
+    index.write()?;
 
     let oid = index.write_tree()?;
     let signature = Signature::now(&user.name, &user.email)?;
@@ -561,7 +743,7 @@ fn set_repo(repo: &Repository, user: &User) -> Result<()> {
         Some("HEAD"),
         &signature,
         &signature,
-        "store initialize",
+        &CommitMessage::new("store", "initialize", None).to_string(),
         &repo.find_tree(oid)?,
         &[],
     )?;
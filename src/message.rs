@@ -0,0 +1,185 @@
+use std::fmt::{Display, Write as _};
+
+const BIN_TRAILER: &str = "Pm-Bin";
+const ACTION_TRAILER: &str = "Pm-Action";
+const VALUE_TRAILER: &str = "Pm-Value";
+const TARGET_TRAILER: &str = "Pm-Target";
+
+/// Structured commit message. The subject line is for humans, the trailers
+/// carry the exact values so labels with spaces, commas or newlines
+/// round-trip.
+pub struct CommitMessage {
+    pub bin: String,
+    pub action: String,
+    pub value: Option<String>,
+    /// Commit acted upon by undo and redo.
+    pub target: Option<String>,
+}
+
+impl CommitMessage {
+    pub fn new(bin: &str, action: &str, value: Option<&str>) -> Self {
+        Self {
+            bin: bin.to_string(),
+            action: action.to_string(),
+            value: value.map(str::to_string),
+            target: None,
+        }
+    }
+
+    pub fn with_target(mut self, target: &str) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn parse(message: &str) -> Self {
+        let mut parsed = Self::new("", "", None);
+
+        for (key, value) in git2::message_trailers_strs(message)
+            .iter()
+            .flat_map(|trailers| trailers.iter().collect::<Vec<_>>())
+        {
+            match key {
+                BIN_TRAILER => parsed.bin = value.to_string(),
+                ACTION_TRAILER => parsed.action = value.to_string(),
+                VALUE_TRAILER => parsed.value = Some(unescape(value)),
+                TARGET_TRAILER => parsed.target = Some(unescape(value)),
+                _ => (),
+            }
+        }
+
+        if parsed.bin.is_empty() {
+            return Self::parse_legacy(message);
+        }
+
+        parsed
+    }
+
+    // Messages written before trailers were "<bin> <action> [value]" with the
+    // value running to the end of the line.
+    fn parse_legacy(message: &str) -> Self {
+        let subject = message.lines().next().unwrap_or_default();
+        let mut parts = subject.splitn(3, ' ');
+
+        Self::new(
+            parts.next().unwrap_or_default(),
+            parts.next().unwrap_or_default(),
+            parts.next(),
+        )
+    }
+
+    pub fn value_or_dash(&self) -> String {
+        self.value.clone().unwrap_or_else(|| "-".to_string())
+    }
+}
+
+impl Display for CommitMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.bin, self.action)?;
+        if let Some(value) = &self.value {
+            write!(f, " {}", value.lines().next().unwrap_or_default())?;
+        }
+
+        write!(
+            f,
+            "\n\n{BIN_TRAILER}: {}\n{ACTION_TRAILER}: {}\n",
+            self.bin, self.action
+        )?;
+        if let Some(value) = &self.value {
+            writeln!(f, "{VALUE_TRAILER}: {}", escape(value))?;
+        }
+        if let Some(target) = &self.target {
+            writeln!(f, "{TARGET_TRAILER}: {}", escape(target))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Percent-encodes what a trailer can't hold: line breaks, and whitespace
+/// at either end, which git trims off. An empty trailer takes the next line
+/// as its value, a lone `%` stands for the empty string instead.
+fn escape(value: &str) -> String {
+    if value.is_empty() {
+        return "%".to_string();
+    }
+
+    let last = value.chars().count() - 1;
+    let mut escaped = String::with_capacity(value.len());
+
+    for (i, c) in value.chars().enumerate() {
+        let edge = i == 0 || i == last;
+        if matches!(c, '%' | '\n' | '\r') || (edge && matches!(c, ' ' | '\t')) {
+            let _ = write!(escaped, "%{:02X}", c as u32);
+        } else {
+            escaped.push(c);
+        }
+    }
+
+    escaped
+}
+
+fn unescape(value: &str) -> String {
+    if value == "%" {
+        return String::new();
+    }
+
+    let bytes = value.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let decoded = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        if let Some(byte) = decoded {
+            unescaped.push(byte);
+            i += 3;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CommitMessage;
+
+    #[test]
+    fn awkward_labels_round_trip() {
+        for label in [
+            "github",
+            "with spaces",
+            " leading",
+            "trailing ",
+            "  both  ",
+            "\ttabs\t",
+            " ",
+            "",
+            "key: value",
+            "Pm-Bin: forged",
+            "two\nlines",
+            "crlf\r\n",
+            "\nsurrounded\n",
+            "%",
+            "100%",
+            "%0A",
+            "%25",
+            "%%0D%",
+            "%zz",
+            "ünïcödé %C3",
+        ] {
+            let message = CommitMessage::new("store", "add", Some(label)).with_target(label);
+            let parsed = CommitMessage::parse(&message.to_string());
+
+            assert_eq!(parsed.bin, "store");
+            assert_eq!(parsed.action, "add");
+            assert_eq!(parsed.value.as_deref(), Some(label), "value {label:?}");
+            assert_eq!(parsed.target.as_deref(), Some(label), "target {label:?}");
+        }
+    }
+}
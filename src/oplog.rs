@@ -0,0 +1,686 @@
+use std::path::Path;
+
+use aes_gcm::{aead::Aead, Aes256Gcm};
+use chrono::Local;
+use git2::{Oid, Repository, Time, Tree};
+use hashbrown::{HashMap, HashSet};
+use owo_colors::OwoColorize;
+use rkyv::AlignedVec;
+use snafu::ResultExt;
+
+use crate::{
+    auth::remote_host,
+    diff::diff,
+    error::{CorruptFileErr, FsErr, NoRevisionErr, Result},
+    layout::to_hex,
+    manager::{format_time, read_blob, Manager},
+    revision::HistoryFilter,
+    store::Item,
+    table::Table,
+    user::Remote,
+};
+
+pub const OPS_DIR: &str = "ops";
+
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone)]
+#[archive(check_bytes)]
+pub enum Op {
+    /// Add or modify an item.
+    Set {
+        label: String,
+        item: Item,
+    },
+    Delete {
+        label: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// Changed user fields, an empty value removes the field.
+    User {
+        fields: Vec<(String, String)>,
+    },
+    /// Cancel the record with this id, undoing an undo redoes its target.
+    Undo {
+        id: String,
+    },
+}
+
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+#[archive(check_bytes)]
+pub struct Record {
+    pub id: String,
+    /// Milliseconds since the epoch.
+    pub time: i64,
+    pub device: String,
+    pub op: Op,
+}
+
+/// Append-only log of store mutations. Every record is its own encrypted
+/// file under `ops/` that is never rewritten, so merging two devices is the
+/// union of their files.
+#[derive(Default)]
+pub struct OpLog {
+    files: HashMap<String, Vec<u8>>,
+    records: Vec<Record>,
+}
+
+impl OpLog {
+    pub fn from_dir(dir: &Path, cipher: &Aes256Gcm) -> Result<Self> {
+        let mut log = Self::default();
+
+        for entry in std::fs::read_dir(dir).context(FsErr {
+            path: dir.display().to_string(),
+        })? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+
+            let data = std::fs::read(&path).context(FsErr {
+                path: path.display().to_string(),
+            })?;
+            log.insert(name.to_string(), data, cipher)?;
+        }
+
+        log.sort();
+
+        Ok(log)
+    }
+
+    /// Reads a log from file names and contents, as kept in a backup.
+    pub fn from_files<'a>(
+        files: impl IntoIterator<Item = (&'a str, &'a Vec<u8>)>,
+        cipher: &Aes256Gcm,
+    ) -> Result<Self> {
+        let mut log = Self::default();
+
+        for (name, data) in files {
+            log.insert(name.to_string(), data.clone(), cipher)?;
+        }
+
+        log.sort();
+
+        Ok(log)
+    }
+
+    pub fn from_tree(repo: &Repository, tree: &Tree, cipher: &Aes256Gcm) -> Result<Self> {
+        let mut log = Self::default();
+
+        for entry in tree {
+            let Some(name) = entry.name() else {
+                continue;
+            };
+
+            log.insert(name.to_string(), read_blob(repo, tree, name)?, cipher)?;
+        }
+
+        log.sort();
+
+        Ok(log)
+    }
+
+    pub const fn files(&self) -> &HashMap<String, Vec<u8>> {
+        &self.files
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    pub fn append(&mut self, op: Op, cipher: &Aes256Gcm) -> Result<()> {
+        let id = to_hex(&rand::random::<[u8; 8]>());
+
+        let record = Record {
+            id: id.clone(),
+            time: Local::now().timestamp_millis(),
+            device: whoami::devicename(),
+            op,
+        };
+
+        let data = rkyv::to_bytes::<_, 256>(&record).map_err(|err| err.to_string())?;
+        let nonce: [u8; 12] = rand::random();
+        let ciphertext = cipher.encrypt(&nonce.into(), data.as_slice())?;
+
+        self.files
+            .insert(id, [nonce.as_slice(), &ciphertext].concat());
+        self.records.push(record);
+
+        Ok(())
+    }
+
+    /// Adds the records of `other` this log does not have yet.
+    pub fn union(&mut self, other: Self) {
+        let known = self.files.keys().cloned().collect::<HashSet<_>>();
+
+        for record in other.records {
+            if !known.contains(&record.id) {
+                self.files
+                    .insert(record.id.clone(), other.files[&record.id].clone());
+                self.records.push(record);
+            }
+        }
+
+        self.sort();
+    }
+
+    /// Drops the records of the item `label` and of the names it was renamed
+    /// from. A rename moving another item away from `label` stops it, the
+    /// records before belong to that item. Undos of dropped records go too.
+    pub fn forget(&mut self, label: &str) {
+        let cancelled = self.cancelled();
+        let mut names = HashSet::new();
+        names.insert(label.to_string());
+        let mut dropped = HashSet::new();
+
+        for record in self.records.iter().rev() {
+            let live = !cancelled.contains(&record.id);
+
+            match &record.op {
+                Op::Set { label, .. } | Op::Delete { label } if names.contains(label) => {
+                    dropped.insert(record.id.clone());
+                }
+                Op::Rename { from, to } if names.contains(to) => {
+                    dropped.insert(record.id.clone());
+                    if live {
+                        names.insert(from.clone());
+                    }
+                }
+                Op::Rename { from, .. } if names.contains(from) => {
+                    if live {
+                        names.remove(from);
+                    } else {
+                        dropped.insert(record.id.clone());
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // oldest first, so undos of dropped undos are dropped as well
+        for record in &self.records {
+            if let Op::Undo { id } = &record.op {
+                if dropped.contains(id) {
+                    dropped.insert(record.id.clone());
+                }
+            }
+        }
+
+        self.records.retain(|record| !dropped.contains(&record.id));
+        self.files.retain(|id, _| !dropped.contains(id));
+    }
+
+    /// Whether any record sets, deletes or renames `label`.
+    pub fn mentions(&self, label: &str) -> bool {
+        self.records.iter().any(|record| match &record.op {
+            Op::Set { label: other, .. } | Op::Delete { label: other } => other == label,
+            Op::Rename { from, to } => from == label || to == label,
+            Op::User { .. } | Op::Undo { .. } => false,
+        })
+    }
+
+    /// Ids of records cancelled by a later undo that is not itself cancelled.
+    pub fn cancelled(&self) -> HashSet<String> {
+        let mut cancelled = HashSet::new();
+
+        for record in self.records.iter().rev() {
+            if let Op::Undo { id } = &record.op {
+                if !cancelled.contains(&record.id) {
+                    cancelled.insert(id.clone());
+                }
+            }
+        }
+
+        cancelled
+    }
+
+    /// Rebuilds the items and user fields by applying every record that is
+    /// not cancelled, oldest first.
+    pub fn replay(&self) -> (HashMap<String, Item>, HashMap<String, String>) {
+        let cancelled = self.cancelled();
+        let mut items = HashMap::new();
+        let mut fields = HashMap::new();
+
+        for record in &self.records {
+            if cancelled.contains(&record.id) {
+                continue;
+            }
+
+            match &record.op {
+                Op::Set { label, item } => {
+                    items.insert(label.clone(), item.clone());
+                }
+                Op::Delete { label } => {
+                    items.remove(label);
+                }
+                Op::Rename { from, to } => {
+                    if let Some(item) = items.remove(from) {
+                        items.insert(to.clone(), item);
+                    }
+                }
+                Op::User { fields: changed } => {
+                    for (key, value) in changed {
+                        if value.is_empty() {
+                            fields.remove(key);
+                        } else {
+                            fields.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+                Op::Undo { .. } => (),
+            }
+        }
+
+        (items, fields)
+    }
+
+    /// Appends the records that turn the replayed state into `items` and
+    /// `fields`, covering changes made without an explicit record.
+    pub fn reconcile(
+        &mut self,
+        items: &HashMap<String, Item>,
+        fields: &HashMap<String, String>,
+        cipher: &Aes256Gcm,
+    ) -> Result<()> {
+        let (replayed_items, replayed_fields) = self.replay();
+
+        let mut labels = items.keys().collect::<Vec<_>>();
+        labels.sort();
+        for label in labels {
+            if replayed_items.get(label) != items.get(label) {
+                self.append(
+                    Op::Set {
+                        label: label.clone(),
+                        item: items[label].clone(),
+                    },
+                    cipher,
+                )?;
+            }
+        }
+
+        let mut deleted = replayed_items
+            .keys()
+            .filter(|label| !items.contains_key(*label))
+            .collect::<Vec<_>>();
+        deleted.sort();
+        for label in deleted {
+            self.append(
+                Op::Delete {
+                    label: label.clone(),
+                },
+                cipher,
+            )?;
+        }
+
+        let mut changed = fields
+            .iter()
+            .filter(|(key, value)| replayed_fields.get(*key) != Some(*value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .chain(
+                replayed_fields
+                    .keys()
+                    .filter(|key| !fields.contains_key(*key))
+                    .map(|key| (key.clone(), String::new())),
+            )
+            .collect::<Vec<_>>();
+        changed.sort();
+        if !changed.is_empty() {
+            self.append(Op::User { fields: changed }, cipher)?;
+        }
+
+        Ok(())
+    }
+
+    fn insert(&mut self, name: String, data: Vec<u8>, cipher: &Aes256Gcm) -> Result<()> {
+        if data.len() < 12 {
+            return Err(CorruptFileErr {
+                path: format!("{OPS_DIR}/{name}"),
+            }
+            .build());
+        }
+
+        let (nonce, ciphertext) = data.split_at(12);
+        let plaintext = cipher.decrypt(nonce.into(), ciphertext)?;
+
+        let mut aligned = AlignedVec::with_capacity(plaintext.len());
+        aligned.extend_from_slice(&plaintext);
+        let record = rkyv::from_bytes::<Record>(&aligned).map_err(|err| err.to_string())?;
+
+        if record.id != name {
+            return Err(CorruptFileErr {
+                path: format!("{OPS_DIR}/{name}"),
+            }
+            .build());
+        }
+
+        self.files.insert(name, data);
+        self.records.push(record);
+
+        Ok(())
+    }
+
+    /// Whether `data` is the intact record file `name`.
+    pub fn is_intact(name: &str, data: &[u8], cipher: &Aes256Gcm) -> bool {
+        Self::default()
+            .insert(name.to_string(), data.to_vec(), cipher)
+            .is_ok()
+    }
+
+    /// The record to undo and the undo to redo. Newest first, cancelled
+    /// records are skipped. Undos before the first other record can be
+    /// redone, except redos themselves: an undo of an undo only cancels it.
+    pub fn op_stack(&self) -> (Option<String>, Option<String>) {
+        let cancelled = self.cancelled();
+        let mut redo = None;
+
+        for record in self.records.iter().rev() {
+            if cancelled.contains(&record.id) {
+                continue;
+            }
+
+            match &record.op {
+                Op::Undo { id } => {
+                    let is_redo = self
+                        .find(id)
+                        .is_some_and(|target| matches!(target.op, Op::Undo { .. }));
+                    if !is_redo {
+                        redo.get_or_insert_with(|| record.id.clone());
+                    }
+                }
+                _ => return (Some(record.id.clone()), redo),
+            }
+        }
+
+        (None, redo)
+    }
+
+    fn sort(&mut self) {
+        self.records
+            .sort_by(|a, b| (a.time, &a.device, &a.id).cmp(&(b.time, &b.device, &b.id)));
+    }
+
+    fn find(&self, id: &str) -> Option<&Record> {
+        self.records
+            .iter()
+            .rev()
+            .find(|record| record.id.starts_with(id))
+    }
+}
+
+impl Manager {
+    /// Replaces the items and user fields with the replayed log.
+    pub fn apply_log(&mut self) -> Result<()> {
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+
+        let (items, fields) = log.replay();
+        self.store.items = items;
+
+        let user = self.user.clone();
+        self.user.merge_fields(&fields, &user);
+        if let (Some(url), None) = (fields.get("remote"), &self.user.remote) {
+            self.user.remote = Some(Remote {
+                host: remote_host(url)?,
+                url: url.clone(),
+                creds: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Merges a remote log. Both sides keep every record, so their union
+    /// needs no three-way merge and has no conflicts.
+    pub fn merge_log(&mut self, remote_log: OpLog, remote_oid: Oid) -> Result<()> {
+        let Some(log) = &mut self.log else {
+            return Ok(());
+        };
+        log.union(remote_log);
+
+        let local_items = self.store.decrypt_items(&self.store_aes)?;
+        self.apply_log()?;
+        for change in diff(&local_items, &self.store.decrypt_items(&self.store_aes)?).concat() {
+            println!("{}", change.bright_cyan());
+        }
+
+        self.merge_parent = Some(remote_oid);
+        self.fs_dirty = true;
+        self.success_message = Some("Successfully merged store from remote".to_string());
+
+        Ok(())
+    }
+
+    pub fn log_history(&mut self, label: Option<&String>, filter: &HistoryFilter) -> Result<()> {
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+
+        let mut table = Table::new([
+            "Device".to_string(),
+            "Action".to_string(),
+            "Value".to_string(),
+            "Time".to_string(),
+            "Id".to_string(),
+        ]);
+
+        // Whether a set adds or modifies depends on the records before it.
+        let mut live = HashSet::new();
+        let mut rows = Vec::new();
+        for record in log.records() {
+            let (action, value, labels) = match &record.op {
+                Op::Set { label, .. } => (
+                    if live.insert(label.clone()) {
+                        "add"
+                    } else {
+                        "modify"
+                    },
+                    label.clone(),
+                    vec![label],
+                ),
+                Op::Delete { label } => {
+                    live.remove(label);
+                    ("delete", label.clone(), vec![label])
+                }
+                Op::Rename { from, to } => {
+                    if live.remove(from) {
+                        live.insert(to.clone());
+                    }
+                    ("rename", format!("{from} -> {to}"), vec![from, to])
+                }
+                Op::User { fields } => (
+                    "set",
+                    fields
+                        .iter()
+                        .map(|(key, _)| key.as_str())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    vec![],
+                ),
+                Op::Undo { id } => (
+                    if log
+                        .find(id)
+                        .is_some_and(|target| matches!(target.op, Op::Undo { .. }))
+                    {
+                        "redo"
+                    } else {
+                        "undo"
+                    },
+                    short(id).to_string(),
+                    vec![],
+                ),
+            };
+
+            if label.is_some_and(|label| !labels.contains(&label)) {
+                continue;
+            }
+
+            if filter.matches_fields(record.time / 1000, action, &[&record.device]) {
+                rows.push([
+                    record.device.clone(),
+                    action.to_string(),
+                    value,
+                    record_time(record)?,
+                    short(&record.id).to_string(),
+                ]);
+            }
+        }
+
+        for row in rows
+            .into_iter()
+            .rev()
+            .take(filter.count.unwrap_or(usize::MAX))
+        {
+            table.insert(row);
+        }
+
+        if table.is_empty() {
+            println!("{}", "No matching operations in the log".bright_red());
+            return Ok(());
+        }
+
+        self.page(table)
+    }
+
+    /// Undoes the latest operation not undone yet, or the one with `id`.
+    pub fn undo_op(&mut self, id: Option<&String>) -> Result<()> {
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+
+        let target = if let Some(id) = id {
+            log.find(id)
+                .ok_or_else(|| NoRevisionErr { rev: id }.build())?
+                .id
+                .clone()
+        } else if let Some(record) = self.op_stack().0 {
+            record
+        } else {
+            println!("{}", "Nothing to undo".bright_red());
+            return Ok(());
+        };
+
+        self.cancel_op(&target, "undid")
+    }
+
+    /// Redoes the latest undo, as long as nothing was done after it.
+    pub fn redo_op(&mut self) -> Result<()> {
+        let Some(target) = self.op_stack().1 else {
+            println!("{}", "Nothing to redo".bright_red());
+            return Ok(());
+        };
+
+        self.cancel_op(&target, "redid")
+    }
+
+    fn op_stack(&self) -> (Option<String>, Option<String>) {
+        self.log.as_ref().map_or((None, None), OpLog::op_stack)
+    }
+
+    fn cancel_op(&mut self, id: &str, done: &str) -> Result<()> {
+        let Some(log) = &mut self.log else {
+            return Ok(());
+        };
+
+        log.append(Op::Undo { id: id.to_string() }, &self.store_aes)?;
+        self.apply_log()?;
+
+        self.fs_dirty = true;
+        self.success_message = Some(format!("Successfully {done} {}", short(id)));
+
+        Ok(())
+    }
+}
+
+fn short(id: &str) -> &str {
+    id.get(..8).unwrap_or(id)
+}
+
+fn record_time(record: &Record) -> Result<String> {
+    let offset = Local::now().offset().local_minus_utc() / 60;
+
+    format_time(Time::new(record.time / 1000, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{Aes256Gcm, KeyInit};
+
+    use super::{Op, OpLog};
+    use crate::store::Item;
+
+    fn set(label: &str) -> Op {
+        Op::Set {
+            label: label.to_string(),
+            item: Item::new([0; 12], label.as_bytes().to_vec()),
+        }
+    }
+
+    fn undo(log: &mut OpLog, id: String, cipher: &Aes256Gcm) {
+        log.append(Op::Undo { id }, cipher).unwrap();
+    }
+
+    fn rename(from: &str, to: &str) -> Op {
+        Op::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn labels(log: &OpLog) -> Vec<String> {
+        let mut labels = log.replay().0.into_keys().collect::<Vec<_>>();
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn forget_keeps_items_renamed_away() {
+        let cipher = Aes256Gcm::new(&[7; 32].into());
+        let mut log = OpLog::default();
+        log.append(set("a"), &cipher).unwrap();
+        log.append(rename("a", "b"), &cipher).unwrap();
+        log.append(set("a"), &cipher).unwrap();
+        log.append(set("c"), &cipher).unwrap();
+        log.append(rename("c", "d"), &cipher).unwrap();
+        let undone = log.records().last().unwrap().id.clone();
+        undo(&mut log, undone, &cipher);
+        log.append(rename("c", "a"), &cipher).unwrap();
+        assert_eq!(labels(&log), ["a", "b"]);
+
+        log.forget("a");
+
+        // the undone rename and its undo are gone with c, b stays
+        assert_eq!(labels(&log), ["b"]);
+        assert_eq!(log.records().len(), 2);
+        assert_eq!(log.files().len(), 2);
+    }
+
+    #[test]
+    fn redo_follows_undo_chain() {
+        let cipher = Aes256Gcm::new(&[7; 32].into());
+        let mut log = OpLog::default();
+        log.append(set("a"), &cipher).unwrap();
+        log.append(set("b"), &cipher).unwrap();
+
+        for _ in 0..2 {
+            let target = log.op_stack().0.unwrap();
+            undo(&mut log, target, &cipher);
+        }
+        assert!(log.replay().0.is_empty());
+        assert_eq!(log.op_stack().0, None);
+
+        let redo = log.op_stack().1.unwrap();
+        undo(&mut log, redo, &cipher);
+        assert_eq!(log.replay().0.keys().collect::<Vec<_>>(), ["a"]);
+
+        // the second redo redoes the first undo instead of cancelling the
+        // first redo
+        let redo = log.op_stack().1.unwrap();
+        undo(&mut log, redo, &cipher);
+        let mut labels = log.replay().0.into_keys().collect::<Vec<_>>();
+        labels.sort();
+        assert_eq!(labels, ["a", "b"]);
+        assert_eq!(log.op_stack().1, None);
+    }
+}
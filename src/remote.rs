@@ -0,0 +1,97 @@
+use owo_colors::OwoColorize;
+
+use crate::{
+    backend::{backend, remote_ref},
+    error::Result,
+    manager::{Manager, ORIGIN},
+    table::Table,
+};
+
+impl Manager {
+    pub fn list_remotes(&self) -> Result<()> {
+        let repo = self.repo()?;
+        let remotes = self.remotes()?;
+
+        if remotes.is_empty() {
+            println!("{}", "Remote not set".bright_red());
+            return Ok(());
+        }
+
+        let local_oid = repo.head()?.peel_to_commit()?.id();
+        let mut table = Table::new(["Name".to_string(), "Url".to_string(), "State".to_string()]);
+
+        for (name, url) in remotes {
+            let state = self.sync_state(&name, local_oid)?;
+            table.insert([name, url, state]);
+        }
+
+        table.display()?;
+
+        Ok(())
+    }
+
+    /// How `main` relates to the last known state of the remote `name`.
+    pub fn sync_state(&self, name: &str, local_oid: git2::Oid) -> Result<String> {
+        let Ok(remote_oid) = self.repo()?.refname_to_id(&remote_ref(name)) else {
+            return Ok(format!("{name}/main not fetched yet"));
+        };
+
+        let (ahead, behind) = self.repo()?.graph_ahead_behind(local_oid, remote_oid)?;
+
+        Ok(if ahead == 0 && behind == 0 {
+            format!("up to date with {name}/main")
+        } else {
+            format!("{ahead} ahead, {behind} behind {name}/main")
+        })
+    }
+
+    pub fn add_remote(&mut self, name: &str, url: &str) -> Result<()> {
+        let repo = self.repo()?;
+
+        if name == ORIGIN {
+            println!(
+                "{}",
+                "origin is shared by all devices, set it with `pm user set --remote`".bright_red()
+            );
+            return Ok(());
+        }
+
+        if repo.find_remote(name).is_ok() {
+            println!("{}", format!("Remote '{name}' already exists").bright_red());
+            return Ok(());
+        }
+
+        // catches malformed bundle urls before the first sync
+        backend(name, url, &self.config)?;
+        repo.remote(name, url)?;
+
+        self.success_message = Some(format!("Successfully added remote '{name}'"));
+
+        Ok(())
+    }
+
+    pub fn remove_remote(&mut self, name: &str) -> Result<()> {
+        let repo = self.repo()?;
+
+        if name == ORIGIN {
+            println!(
+                "{}",
+                "origin is shared by all devices, remove it with `pm user set --remote -`"
+                    .bright_red()
+            );
+            return Ok(());
+        }
+
+        if repo.find_remote(name).is_err() {
+            println!("{}", format!("No remote named '{name}'").bright_red());
+            return Ok(());
+        }
+
+        repo.remote_delete(name)?;
+        self.config.set_sync_version(name, None)?;
+
+        self.success_message = Some(format!("Successfully removed remote '{name}'"));
+
+        Ok(())
+    }
+}
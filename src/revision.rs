@@ -0,0 +1,509 @@
+use aes_gcm::{aead::Aead, Aes256Gcm};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use git2::Commit;
+use hashbrown::HashMap;
+use owo_colors::OwoColorize;
+use snafu::OptionExt;
+
+use crate::{
+    diff::{diff, Item as Change},
+    error::{InvalidDateErr, InvalidShortIdErr, NoRevisionErr, Result},
+    manager::{format_time, read_blob, Manager, USER_BIN_PATH},
+    message::CommitMessage,
+    store::{Item, Store},
+    table::Table,
+    user::User,
+};
+
+#[derive(Clone)]
+pub struct HistoryFilter {
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub action: Option<String>,
+    pub author: Option<String>,
+    pub count: Option<usize>,
+}
+
+impl HistoryFilter {
+    pub fn new(
+        since: Option<&String>,
+        until: Option<&String>,
+        action: Option<&String>,
+        author: Option<&String>,
+        count: Option<usize>,
+    ) -> Result<Self> {
+        let date = |date: &String, end_of_day| {
+            parse_date(date, end_of_day).context(InvalidDateErr { date })
+        };
+
+        Ok(Self {
+            since: since.map(|since| date(since, false)).transpose()?,
+            until: until.map(|until| date(until, true)).transpose()?,
+            action: action.cloned(),
+            author: author.map(|author| author.to_lowercase()),
+            count,
+        })
+    }
+
+    pub fn matches(&self, commit: &Commit, action: &str) -> bool {
+        let author = commit.author();
+        let authors = [author.name(), author.email()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        self.matches_fields(commit.time().seconds(), action, &authors)
+    }
+
+    /// Matches anything with a time in seconds, an action and author names.
+    pub fn matches_fields(&self, time: i64, action: &str, authors: &[&str]) -> bool {
+        self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+            && self.action.as_ref().is_none_or(|wanted| wanted == action)
+            && self.author.as_ref().is_none_or(|wanted| {
+                authors
+                    .iter()
+                    .any(|field| field.to_lowercase().contains(wanted))
+            })
+    }
+
+    pub fn is_full(&self, shown: usize) -> bool {
+        self.count.is_some_and(|count| shown >= count)
+    }
+}
+
+pub struct Snapshot {
+    pub items: HashMap<String, Vec<u8>>,
+    pub user: HashMap<String, String>,
+}
+
+pub struct State {
+    pub store: Store,
+    pub items: HashMap<String, Vec<u8>>,
+    pub user: User,
+    pub user_nonce: [u8; 12],
+}
+
+impl Manager {
+    pub fn state_at(&self, commit: &Commit, ciphers: &mut Vec<Aes256Gcm>) -> Result<State> {
+        let tree = commit.tree()?;
+        let (store, cipher) = self.store_at(&tree, ciphers)?;
+        let user_blob = read_blob(self.repo()?, &tree, USER_BIN_PATH)?;
+        let (user_nonce, user) = User::from_bytes(&user_blob, &cipher)?;
+
+        Ok(State {
+            items: store.decrypt_items(&cipher)?,
+            store,
+            user,
+            user_nonce,
+        })
+    }
+
+    pub fn resolve_revision(&self, rev: &str) -> Result<Commit<'_>> {
+        let err = match self.repo()?.revparse_single(rev) {
+            Ok(object) => return Ok(object.peel_to_commit()?),
+            Err(err) => err,
+        };
+
+        let Some(timestamp) = parse_date(rev, true) else {
+            return Err(err.into());
+        };
+
+        let mut revwalk = self.repo()?.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.simplify_first_parent()?;
+
+        for oid in revwalk {
+            let commit = self.repo()?.find_commit(oid?)?;
+            if commit.time().seconds() <= timestamp {
+                return Ok(commit);
+            }
+        }
+
+        Err(NoRevisionErr { rev }.build())
+    }
+
+    pub fn show(&self, label: &str, at: Option<&String>) -> Result<()> {
+        // the current value comes from the store, plain mode has no revisions
+        let (value, commit) = if let Some(at) = at {
+            let commit = self.resolve_revision(at)?;
+            let mut ciphers = vec![self.store_aes.clone()];
+
+            (
+                self.label_value(&commit, label, &mut ciphers)?,
+                Some(commit),
+            )
+        } else {
+            let value = self
+                .store
+                .items
+                .get(label)
+                .map(|Item { nonce, password }| {
+                    self.store_aes.decrypt(nonce.into(), password.as_slice())
+                })
+                .transpose()?;
+            let head = self
+                .repo
+                .as_ref()
+                .and_then(|repo| repo.head().ok()?.peel_to_commit().ok());
+
+            (value, head)
+        };
+
+        let Some(value) = value else {
+            println!(
+                "{}",
+                if at.is_some() {
+                    format!("No item '{label}' at this revision")
+                } else {
+                    format!("No item '{label}' in store")
+                }
+                .bright_red()
+            );
+            return Ok(());
+        };
+
+        let mut table = Table::new([
+            "Label".to_string(),
+            "Password".to_string(),
+            "Time".to_string(),
+            "Id".to_string(),
+        ]);
+
+        table.insert([
+            label.to_string(),
+            String::from_utf8(value)?,
+            commit
+                .as_ref()
+                .map_or_else(|| Ok("-".to_string()), |commit| format_time(commit.time()))?,
+            commit
+                .as_ref()
+                .map_or_else(|| Ok("-".to_string()), short_id)?,
+        ]);
+
+        table.display()?;
+
+        Ok(())
+    }
+
+    pub fn label_log(&mut self, label: &str, filter: &HistoryFilter) -> Result<()> {
+        let mut revwalk = self.repo()?.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.simplify_first_parent()?;
+
+        let mut table = Table::new(["Action".to_string(), "Time".to_string(), "Id".to_string()]);
+        let mut shown = 0;
+
+        let mut ciphers = vec![self.store_aes.clone()];
+
+        // the label filter speaks in commit actions, the rows in changes
+        let filter = HistoryFilter {
+            action: filter.action.as_deref().map(|action| {
+                match action {
+                    "add" => "added",
+                    "delete" => "deleted",
+                    "modify" => "modified",
+                    action => action,
+                }
+                .to_string()
+            }),
+            ..filter.clone()
+        };
+
+        let mut newer: Option<(Commit, Option<Vec<u8>>)> = None;
+        for oid in revwalk.map(Some).chain([None]) {
+            let (commit, value) = match oid {
+                Some(oid) => {
+                    let commit = self.repo()?.find_commit(oid?)?;
+                    let value = self.label_value(&commit, label, &mut ciphers)?;
+                    (Some(commit), value)
+                }
+                None => (None, None),
+            };
+
+            if let Some((newer_commit, newer_value)) = newer.take() {
+                if let Some(action) = label_change(value.as_ref(), newer_value.as_ref()) {
+                    if filter.matches(&newer_commit, action) {
+                        table.insert([
+                            action.to_string(),
+                            format_time(newer_commit.time())?,
+                            short_id(&newer_commit)?,
+                        ]);
+                        shown += 1;
+                    }
+                }
+            }
+
+            if filter.is_full(shown) {
+                break;
+            }
+
+            newer = commit.map(|commit| (commit, value));
+        }
+
+        if table.is_empty() {
+            println!("{}", format!("No history for '{label}'").bright_red());
+            return Ok(());
+        }
+
+        drop(newer);
+        self.page(table)
+    }
+
+    fn label_value(
+        &self,
+        commit: &Commit,
+        label: &str,
+        ciphers: &mut Vec<Aes256Gcm>,
+    ) -> Result<Option<Vec<u8>>> {
+        let (store, cipher) = self.store_at(&commit.tree()?, ciphers)?;
+
+        let Some(Item { nonce, password }) = store.items.get(label) else {
+            return Ok(None);
+        };
+
+        Ok(Some(cipher.decrypt(nonce.into(), password.as_slice())?))
+    }
+
+    pub fn snapshot(&self, commit: &Commit, ciphers: &mut Vec<Aes256Gcm>) -> Result<Snapshot> {
+        let tree = commit.tree()?;
+        let (store, cipher) = self.store_at(&tree, ciphers)?;
+        let user_blob = read_blob(self.repo()?, &tree, USER_BIN_PATH)?;
+        let (_, user) = User::from_bytes(&user_blob, &cipher)?;
+
+        Ok(Snapshot {
+            items: store.decrypt_items(&cipher)?,
+            user: user.to_hashmap(),
+        })
+    }
+
+    pub fn diff_revisions(
+        &self,
+        rev_a: Option<&String>,
+        rev_b: Option<&String>,
+        reveal: bool,
+    ) -> Result<()> {
+        let new = self.resolve_revision(rev_b.map_or("HEAD", String::as_str))?;
+        let old = match rev_a {
+            Some(rev) => Some(self.resolve_revision(rev)?),
+            None => new.parents().next(),
+        };
+
+        let mut ciphers = vec![self.store_aes.clone()];
+        let new = self.snapshot(&new, &mut ciphers)?;
+        let old = match old {
+            Some(commit) => self.snapshot(&commit, &mut ciphers)?,
+            None => Snapshot {
+                items: HashMap::new(),
+                user: HashMap::new(),
+            },
+        };
+
+        let mut changes = diff(&old.items, &new.items).concat();
+        let mut user_changes = diff(&old.user, &new.user).concat();
+
+        if changes.is_empty() && user_changes.is_empty() {
+            println!("{}", "No changes".bright_green());
+            return Ok(());
+        }
+
+        changes.sort_by(|a, b| a.key().cmp(b.key()));
+        user_changes.sort_by(|a, b| a.key().cmp(b.key()));
+
+        for change in changes {
+            println!("{}", change.bright_cyan());
+
+            if reveal {
+                print_values(
+                    &change,
+                    |label, snapshot| {
+                        snapshot
+                            .items
+                            .get(label)
+                            .map(|value| String::from_utf8_lossy(value).to_string())
+                    },
+                    &old,
+                    &new,
+                );
+            }
+        }
+
+        for change in user_changes {
+            println!("{} {}", "user".bright_cyan(), change.bright_cyan());
+
+            if reveal {
+                print_values(
+                    &change,
+                    |field, snapshot| snapshot.user.get(field).cloned(),
+                    &old,
+                    &new,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn checkout(&mut self, rev: &str) -> Result<()> {
+        let commit = self.resolve_revision(rev)?;
+        let target = commit.id();
+        let short_id = short_id(&commit)?;
+        let time = format_time(commit.time())?;
+
+        let mut ciphers = vec![self.store_aes.clone()];
+        let state = self.state_at(&commit, &mut ciphers)?;
+        drop(commit);
+
+        // unlock_foreign only adds a cipher when the revision used another key
+        if !self.apply_state(state, ciphers.len() == 1, &short_id, &time)? {
+            return Ok(());
+        }
+
+        self.commit_message = Some(
+            CommitMessage::new("store", "checkout", Some(&short_id))
+                .with_target(&target.to_string())
+                .to_string(),
+        );
+        self.success_message = Some(format!("Successfully restored store to {short_id}"));
+
+        Ok(())
+    }
+
+    /// Shows what restoring `state` changes and replaces the store with it
+    /// once confirmed. Items are re-encrypted unless `same_key`. The current
+    /// key stays, reverting to the key of `state` takes its own confirmation.
+    /// Returns whether it restored.
+    pub fn apply_state(
+        &mut self,
+        state: State,
+        same_key: bool,
+        name: &str,
+        time: &str,
+    ) -> Result<bool> {
+        let mut changes = diff(&self.store.decrypt_items(&self.store_aes)?, &state.items).concat();
+        let mut user_changes = diff(&self.user.to_hashmap(), &state.user.to_hashmap()).concat();
+        // same data key, but wrapped for the key used then
+        let key_differs =
+            same_key && (self.store.key != state.store.key || self.store.salt != state.store.salt);
+
+        if changes.is_empty() && user_changes.is_empty() && !key_differs {
+            println!("{}", format!("Store already matches {name}").bright_green());
+            return Ok(false);
+        }
+
+        changes.sort_by(|a, b| a.key().cmp(b.key()));
+        user_changes.sort_by(|a, b| a.key().cmp(b.key()));
+
+        println!("{}", format!("Restoring {name} ({time}):").bright_yellow());
+        for change in changes {
+            println!("  {}", change.bright_cyan());
+        }
+        for change in user_changes {
+            println!("  user {}", change.bright_cyan());
+        }
+        if key_differs {
+            println!(
+                "  {}",
+                "your key changed since, the current key is kept".bright_cyan()
+            );
+        }
+
+        if !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Restore the store to {name}?"))
+            .interact()?
+        {
+            return Ok(false);
+        }
+
+        let revert_key = key_differs
+            && Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!(
+                    "Also revert your key to the one used at {name}? Your current key stops working"
+                ))
+                .default(false)
+                .interact()?;
+
+        let mut items = HashMap::new();
+        for (label, plaintext) in &state.items {
+            let item = if same_key {
+                state.store.items[label].clone()
+            } else {
+                self.encrypt_item(plaintext)?
+            };
+
+            items.insert(label.clone(), item);
+        }
+        self.store.items = items;
+
+        if revert_key {
+            self.store.key = state.store.key;
+            self.store.nonce = state.store.nonce;
+            self.store.salt = state.store.salt;
+        }
+        self.user = state.user;
+        if self.repo.is_some() {
+            self.sync_origin()?;
+        }
+
+        self.fs_dirty = true;
+
+        Ok(true)
+    }
+}
+
+fn label_change(old: Option<&Vec<u8>>, new: Option<&Vec<u8>>) -> Option<&'static str> {
+    match (old, new) {
+        (None, Some(_)) => Some("added"),
+        (Some(_), None) => Some("deleted"),
+        (Some(old), Some(new)) if old != new => Some("modified"),
+        _ => None,
+    }
+}
+
+pub fn short_id(commit: &Commit) -> Result<String> {
+    Ok(commit
+        .as_object()
+        .short_id()?
+        .as_str()
+        .context(InvalidShortIdErr)?
+        .to_string())
+}
+
+// Dates are local time. A bare date means the end of that day when used as
+// an upper bound, so `--at 2024-05-01` includes the changes made on it.
+fn parse_date(date: &str, end_of_day: bool) -> Option<i64> {
+    let naive = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M")
+        .ok()
+        .or_else(|| {
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+
+            if end_of_day {
+                date.and_hms_opt(23, 59, 59)
+            } else {
+                date.and_hms_opt(0, 0, 0)
+            }
+        })?;
+
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.timestamp())
+}
+
+fn print_values(
+    change: &Change<String>,
+    value: impl Fn(&str, &Snapshot) -> Option<String>,
+    old: &Snapshot,
+    new: &Snapshot,
+) {
+    let key = change.key();
+
+    if let Some(old) = value(key, old) {
+        println!("  {} {}", "-".bright_red(), old.bright_red());
+    }
+
+    if let Some(new) = value(key, new) {
+        println!("  {} {}", "+".bright_green(), new.bright_green());
+    }
+}
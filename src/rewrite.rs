@@ -0,0 +1,426 @@
+use std::process::Command;
+
+use aes_gcm::{aead::Aead, Aes256Gcm};
+use chrono::{DateTime, Local};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use git2::{build::CheckoutBuilder, Commit, Oid, Sort};
+use hashbrown::{HashMap, HashSet};
+use owo_colors::OwoColorize;
+
+use crate::{
+    backend::remote_ref,
+    config::Snapshots,
+    erase::Wiper,
+    error::Result,
+    manager::{Manager, STORE_BIN_PATH, USER_BIN_PATH},
+    message::CommitMessage,
+    revision::short_id,
+    store::Item,
+};
+
+pub enum Rewrite {
+    /// Keep the commit, optionally with a new tree and message.
+    Keep {
+        tree: Option<Oid>,
+        message: Option<String>,
+    },
+    /// Drop the commit, its children attach to its first parent.
+    Drop,
+}
+
+impl Manager {
+    pub fn purge(&mut self, label: &str, keep_current: bool) -> Result<()> {
+        let mut ciphers = vec![self.store_aes.clone()];
+        let (affected, place) = if self.repo.is_some() {
+            (self.commits_with(label, &mut ciphers)?, "commit(s)")
+        } else {
+            (self.journal_entries_with(label)?, "undo journal entries")
+        };
+
+        if affected == 0 {
+            println!("{}", format!("No item '{label}' in history").bright_red());
+            return Ok(());
+        }
+
+        println!(
+            "{}",
+            format!("'{label}' is stored in {affected} {place}").bright_yellow()
+        );
+        if !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Rewrite the whole history to remove it?")
+            .interact()?
+        {
+            return Ok(());
+        }
+
+        // Re-encrypting with a fresh nonce leaves no ciphertext of the
+        // current value anywhere in the old history.
+        let current = match self.store.items.get(label) {
+            Some(Item { nonce, password }) if keep_current => {
+                let plaintext = self.store_aes.decrypt(nonce.into(), password.as_slice())?;
+                Some(self.encrypt_item(&plaintext)?)
+            }
+            _ => None,
+        };
+
+        let mut wiper = Wiper::default();
+        let mut cleaned = vec![];
+
+        if self.repo.is_none() {
+            self.purge_journal(label, current, &mut wiper)?;
+            self.success_message = Some(format!(
+                "Successfully purged '{label}' from the undo journal"
+            ));
+        } else {
+            let new_head = self.purge_history(label, current, &mut ciphers)?;
+
+            println!(
+                "{}",
+                "History was rewritten. Run `pm store sync --force` to replace the remote history, other devices must clone again."
+                    .bright_yellow()
+            );
+            self.success_message = Some(format!(
+                "Successfully purged '{label}' from history, now at {}",
+                short_id(&self.repo()?.find_commit(new_head)?)?
+            ));
+        }
+
+        // copies of the old files kept outside the history
+        let baks = [STORE_BIN_PATH, USER_BIN_PATH]
+            .map(|path| self.data_dir.join(format!("{path}.bak")))
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect::<Vec<_>>();
+        for bak in &baks {
+            wiper.file(bak);
+        }
+        if !baks.is_empty() {
+            cleaned.push(format!("{} .bak file(s)", baks.len()));
+        }
+
+        let backups = self.purge_backups(label, &mut ciphers, &mut wiper)?;
+        if backups > 0 {
+            cleaned.push(format!("{backups} backup(s)"));
+        }
+
+        if !cleaned.is_empty() {
+            println!(
+                "{}",
+                format!("Removed '{label}' from {} too", cleaned.join(" and ")).bright_yellow()
+            );
+        }
+        wiper.report();
+
+        Ok(())
+    }
+
+    fn commits_with(&self, label: &str, ciphers: &mut Vec<Aes256Gcm>) -> Result<usize> {
+        let mut revwalk = self.repo()?.revwalk()?;
+        revwalk.push_head()?;
+
+        let mut count = 0;
+        for oid in revwalk {
+            let tree = self.repo()?.find_commit(oid?)?.tree()?;
+            if self.store_at(&tree, ciphers)?.0.items.contains_key(label) {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Rewrites every commit without `label`, the head keeps `current`.
+    /// Returns the new head.
+    fn purge_history(
+        &mut self,
+        label: &str,
+        current: Option<Item>,
+        ciphers: &mut Vec<Aes256Gcm>,
+    ) -> Result<Oid> {
+        let head = self.repo()?.head()?.peel_to_commit()?.id();
+        let new_head = self.rewrite_history(|manager, commit| {
+            let tree = commit.tree()?;
+            let (mut store, cipher) = manager.store_at(&tree, ciphers)?;
+
+            store.items.remove(label);
+            if commit.id() == head {
+                if let Some(item) = &current {
+                    store.items.insert(label.to_string(), item.clone());
+                }
+            }
+
+            Ok(Rewrite::Keep {
+                tree: Some(manager.store_tree(&tree, &store, &cipher, Some(label))?),
+                message: None,
+            })
+        })?;
+
+        match current {
+            Some(item) => {
+                self.store.items.insert(label.to_string(), item);
+            }
+            None => {
+                self.store.items.remove(label);
+            }
+        }
+        self.load_layout()?;
+
+        self.gc();
+
+        Ok(new_head)
+    }
+
+    pub fn compact(&mut self, dry_run: bool) -> Result<()> {
+        let days = self.config.retain_days();
+        let cutoff = Local::now().timestamp() - i64::from(days) * 86_400;
+        let snapshots = self.config.retain_snapshots();
+
+        let mut revwalk = self.repo()?.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.simplify_first_parent()?;
+
+        // Newest first, so the first commit seen in a period is its snapshot.
+        let mut main_line = HashSet::new();
+        let mut kept = HashMap::new();
+        let mut periods = HashSet::new();
+        let mut old = 0;
+        for oid in revwalk {
+            let oid = oid?;
+            let time = self.repo()?.find_commit(oid)?.time().seconds();
+            main_line.insert(oid);
+
+            if time < cutoff {
+                old += 1;
+                let period = period(time, snapshots);
+                if periods.insert(period.clone()) {
+                    kept.insert(oid, period);
+                }
+            }
+        }
+
+        let mut revwalk = self.repo()?.revwalk()?;
+        revwalk.push_head()?;
+        for oid in revwalk {
+            let oid = oid?;
+            if !main_line.contains(&oid) && self.repo()?.find_commit(oid)?.time().seconds() < cutoff
+            {
+                old += 1;
+            }
+        }
+
+        if old == kept.len() {
+            println!(
+                "{}",
+                format!("Nothing older than {days} days to squash").bright_green()
+            );
+            if !dry_run {
+                self.gc();
+                self.success_message = Some("Successfully ran git gc".to_string());
+            }
+            return Ok(());
+        }
+
+        println!(
+            "{}",
+            format!(
+                "{old} commit(s) older than {days} days squash into {} snapshot(s)",
+                kept.len()
+            )
+            .bright_yellow()
+        );
+
+        let mut snapshot_list = kept.iter().collect::<Vec<_>>();
+        snapshot_list.sort_by(|a, b| a.1.cmp(b.1));
+        for (oid, period) in snapshot_list {
+            println!(
+                "  {} {}",
+                period.bright_cyan(),
+                short_id(&self.repo()?.find_commit(*oid)?)?
+            );
+        }
+
+        if dry_run
+            || !Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt("Rewrite history now?")
+                .interact()?
+        {
+            return Ok(());
+        }
+
+        self.rewrite_history(|_, commit| {
+            if commit.time().seconds() >= cutoff {
+                return Ok(Rewrite::Keep {
+                    tree: None,
+                    message: None,
+                });
+            }
+
+            Ok(kept
+                .get(&commit.id())
+                .map_or(Rewrite::Drop, |period| Rewrite::Keep {
+                    tree: None,
+                    message: Some(
+                        CommitMessage::new("store", "snapshot", Some(period)).to_string(),
+                    ),
+                }))
+        })?;
+
+        self.gc();
+
+        println!(
+            "{}",
+            "History was rewritten. Run `pm store sync --force` to replace the remote history, other devices must clone again."
+                .bright_yellow()
+        );
+        self.success_message = Some(format!(
+            "Successfully squashed {} commit(s)",
+            old - kept.len()
+        ));
+
+        Ok(())
+    }
+
+    /// Rewrites every commit reachable from HEAD, oldest first, as decided
+    /// by `plan`. Dropped commits are replaced by their nearest kept first
+    /// parent ancestor. Undo targets are remapped to the rewritten commits.
+    /// Returns the new HEAD.
+    pub fn rewrite_history(
+        &self,
+        mut plan: impl FnMut(&Self, &Commit) -> Result<Rewrite>,
+    ) -> Result<Oid> {
+        let mut revwalk = self.repo()?.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+        let mut rewritten = HashMap::new();
+        let mut new_head = None;
+
+        for oid in revwalk {
+            let commit = self.repo()?.find_commit(oid?)?;
+            let mapped_parents = commit
+                .parent_ids()
+                .filter_map(|parent| rewritten.get(&parent).copied())
+                .collect::<Vec<Oid>>();
+
+            let (new_tree, message) = match plan(self, &commit)? {
+                Rewrite::Drop => {
+                    if let Some(&parent) = mapped_parents.first() {
+                        rewritten.insert(commit.id(), parent);
+                        new_head = Some(parent);
+                    }
+                    continue;
+                }
+                Rewrite::Keep { tree, message } => (tree, message),
+            };
+
+            let tree = match new_tree {
+                Some(oid) => self.repo()?.find_tree(oid)?,
+                None => commit.tree()?,
+            };
+
+            // Parents that collapsed onto an ancestor of the first parent
+            // carry no history of their own anymore.
+            let mut parents: Vec<Commit> = Vec::new();
+            for (i, &parent) in mapped_parents.iter().enumerate() {
+                let redundant = i > 0
+                    && (mapped_parents[0] == parent
+                        || self
+                            .repo()?
+                            .graph_descendant_of(mapped_parents[0], parent)?);
+
+                if !redundant {
+                    parents.push(self.repo()?.find_commit(parent)?);
+                }
+            }
+
+            let message = match message {
+                Some(message) => message,
+                None => self.remap_message(&commit, &rewritten)?,
+            };
+
+            let new_oid = self.repo()?.commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                &message,
+                &tree,
+                &parents.iter().collect::<Vec<_>>(),
+            )?;
+
+            rewritten.insert(commit.id(), new_oid);
+            new_head = Some(new_oid);
+        }
+
+        let new_head = new_head.ok_or_else(|| git2::Error::from_str("empty history"))?;
+
+        self.repo()?
+            .reference("refs/heads/main", new_head, true, "pm: rewrite history")?;
+        self.repo()?
+            .checkout_head(Some(CheckoutBuilder::new().force()))?;
+
+        // The remote tracking branches still point at the old history, they
+        // are fetched again on the next sync.
+        for (name, _) in self.remotes()? {
+            if let Ok(mut reference) = self.repo()?.find_reference(&remote_ref(&name)) {
+                reference.delete()?;
+            }
+        }
+
+        Ok(new_head)
+    }
+
+    fn remap_message(&self, commit: &Commit, rewritten: &HashMap<Oid, Oid>) -> Result<String> {
+        let original = String::from_utf8_lossy(commit.message_bytes()).to_string();
+        let mut message = CommitMessage::parse(&original);
+
+        let Some(target) = message
+            .target
+            .as_deref()
+            .and_then(|target| Oid::from_str(target).ok())
+            .and_then(|target| rewritten.get(&target))
+        else {
+            return Ok(original);
+        };
+
+        message.value = Some(short_id(&self.repo()?.find_commit(*target)?)?);
+        message.target = Some(target.to_string());
+
+        Ok(message.to_string())
+    }
+
+    /// Drops reflogs and prunes objects that are no longer reachable, so
+    /// rewritten content does not linger in `.git/objects`.
+    pub fn gc(&self) {
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .arg("-C")
+                .arg(&self.data_dir)
+                .args(args)
+                .output()
+                .is_ok_and(|output| output.status.success())
+        };
+
+        if !(git(&["reflog", "expire", "--expire=now", "--all"])
+            && git(&["gc", "--prune=now", "--quiet"]))
+        {
+            println!(
+                "{}",
+                "Could not run git gc, old objects may remain until git gc --prune=now is run"
+                    .bright_yellow()
+            );
+        }
+    }
+}
+
+fn period(time: i64, snapshots: Snapshots) -> String {
+    let time = DateTime::from_timestamp(time, 0)
+        .unwrap_or_default()
+        .with_timezone(&Local);
+
+    match snapshots {
+        Snapshots::Monthly => time.format("%Y-%m"),
+        Snapshots::Weekly => time.format("%G-W%V"),
+        Snapshots::None => return "before retention".to_string(),
+    }
+    .to_string()
+}
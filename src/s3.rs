@@ -0,0 +1,216 @@
+use std::{cell::RefCell, fmt::Write as _};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{
+    auth::CredentialHelper,
+    backend::{read_body, send, BundleStore},
+    error::{HostErr, HttpErr, NoBucketErr, Result, SyncConflictErr},
+    layout::to_hex,
+    user::Credentials,
+};
+
+const BUNDLE_NAME: &str = "pm.bundle";
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Bundle in an S3-compatible bucket, versioned by its `ETag`. The url is
+/// path-style, `s3://host/bucket/prefix?region=eu-west-1`, and `s3+http://`
+/// talks plain http for a local `MinIO`.
+pub struct S3Store {
+    endpoint: String,
+    host: String,
+    /// Path of the bundle object, starting with the bucket.
+    path: String,
+    region: String,
+    helper: CredentialHelper,
+    /// Filled once per sync, a push takes more than one request.
+    credentials: RefCell<Option<Credentials>>,
+}
+
+impl S3Store {
+    pub fn new(url: &str) -> Result<Self> {
+        let parsed = Url::parse(url)?;
+        let host = match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(HostErr.build()),
+        };
+
+        let scheme = if parsed.scheme() == "s3+http" {
+            "http"
+        } else {
+            "https"
+        };
+
+        let prefix = parsed.path().trim_matches('/');
+        if prefix.is_empty() {
+            return Err(NoBucketErr.build());
+        }
+
+        let region = parsed
+            .query_pairs()
+            .find(|(key, _)| key == "region")
+            .map_or_else(
+                || DEFAULT_REGION.to_string(),
+                |(_, region)| region.to_string(),
+            );
+
+        Ok(Self {
+            endpoint: format!("{scheme}://{host}"),
+            host,
+            path: format!("/{}/{BUNDLE_NAME}", encode_path(prefix)),
+            region,
+            helper: CredentialHelper::new(url),
+            credentials: RefCell::new(None),
+        })
+    }
+
+    /// Access key and secret from `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY`, or the credential helper.
+    fn credentials(&self) -> Result<Credentials> {
+        if let Some(credentials) = self.credentials.borrow().as_ref() {
+            return Ok(credentials.clone());
+        }
+
+        let credentials = match (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(username), Ok(password)) => Credentials { username, password },
+            _ => self.helper.fill()?,
+        };
+        *self.credentials.borrow_mut() = Some(credentials.clone());
+
+        Ok(credentials)
+    }
+
+    /// Request signed with AWS signature version 4.
+    fn request(
+        &self,
+        method: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<ureq::Response> {
+        let Credentials { username, password } = self.credentials()?;
+
+        let now = Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = to_hex(&Sha256::digest(body));
+
+        let canonical_request = format!(
+            "{method}\n{}\n\nhost:{}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{timestamp}\n\n{SIGNED_HEADERS}\n{payload_hash}",
+            self.path, self.host
+        );
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            to_hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [date.as_str(), &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{password}").into_bytes(), |key, part| {
+                hmac(&key, part.as_bytes())
+            });
+        let signature = to_hex(&hmac(&signing_key, string_to_sign.as_bytes()));
+
+        let mut request = ureq::request(method, &format!("{}{}", self.endpoint, self.path))
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &timestamp)
+            .set(
+                "Authorization",
+                &format!(
+                    "AWS4-HMAC-SHA256 Credential={username}/{scope}, SignedHeaders={SIGNED_HEADERS}, Signature={signature}"
+                ),
+            );
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+
+        let response = send(request, body)?;
+        if matches!(response.status(), 401 | 403) {
+            self.helper.reject();
+            self.credentials.take();
+        } else {
+            // only credentials from the helper are handed back to it
+            let _ = self.helper.approve();
+        }
+
+        Ok(response)
+    }
+}
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+impl BundleStore for S3Store {
+    fn get(&self) -> Result<Option<(Vec<u8>, String)>> {
+        let response = self.request("GET", &[], &[])?;
+        if response.status() == 404 {
+            return Ok(None);
+        }
+
+        let etag = response.header("ETag").map(ToString::to_string);
+        let bundle = read_body(response)?;
+
+        let version = etag.ok_or_else(|| {
+            HttpErr {
+                message: "bucket sent no ETag for the bundle",
+            }
+            .build()
+        })?;
+
+        Ok(Some((bundle, version)))
+    }
+
+    fn put(&self, bundle: &[u8], expected: Option<&str>, force: bool) -> Result<String> {
+        let condition = match (force, expected) {
+            (true, _) => vec![],
+            (false, Some(version)) => vec![("If-Match", version)],
+            (false, None) => vec![("If-None-Match", "*")],
+        };
+
+        let response = self.request("PUT", &condition, bundle)?;
+
+        // 409 is sent when a concurrent conditional write won
+        if matches!(response.status(), 409 | 412) {
+            return Err(SyncConflictErr.build());
+        }
+
+        let etag = response.header("ETag").map(ToString::to_string);
+        read_body(response)?;
+
+        etag.ok_or_else(|| {
+            HttpErr {
+                message: "bucket sent no ETag for the bundle",
+            }
+            .build()
+        })
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(data);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes every byte of the path but unreserved ones and `/`, as
+/// the canonical request requires.
+fn encode_path(path: &str) -> String {
+    path.bytes().fold(String::new(), |mut encoded, byte| {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+
+        encoded
+    })
+}
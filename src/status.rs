@@ -0,0 +1,154 @@
+use std::path::Path;
+
+use git2::{Oid, Status};
+use owo_colors::OwoColorize;
+
+use crate::{
+    diff::diff,
+    error::Result,
+    manager::{Manager, REMOTE_MAIN_REF, STORE_BIN_PATH, USER_BIN_PATH},
+};
+
+impl Manager {
+    pub fn status(&mut self) -> Result<()> {
+        if self.repo.is_none() {
+            println!("{}: {}", "Mode".bright_yellow(), "plain".bright_cyan());
+            println!(
+                "{}: {}",
+                "Layout".bright_yellow(),
+                self.layout().name().bright_cyan()
+            );
+            println!(
+                "{}: {}",
+                "Undo journal".bright_yellow(),
+                format!("{} change(s)", self.journal_depth()?).bright_cyan()
+            );
+
+            return Ok(());
+        }
+
+        let remotes = self.remotes()?;
+        if remotes.is_empty() {
+            println!("{}: {}", "Remote".bright_yellow(), "Not set".bright_cyan());
+        }
+
+        let local_oid = self.repo()?.head()?.peel_to_commit()?.id();
+
+        for (name, url) in &remotes {
+            println!(
+                "{}: {}",
+                format!("Remote {name}").bright_yellow(),
+                url.bright_cyan()
+            );
+
+            if let Err(err) = self.fetch_from(name) {
+                println!(
+                    "{}",
+                    format!("Could not fetch {name}, showing last known remote state: {err}")
+                        .bright_yellow()
+                );
+            }
+
+            println!(
+                "{}: {}",
+                "Branch main".bright_yellow(),
+                self.sync_state(name, local_oid)?.bright_cyan()
+            );
+        }
+
+        let remote_oid = self.repo()?.refname_to_id(REMOTE_MAIN_REF).ok();
+
+        println!(
+            "{}: {}",
+            "Layout".bright_yellow(),
+            self.layout().name().bright_cyan()
+        );
+
+        for path in [STORE_BIN_PATH, USER_BIN_PATH] {
+            let status = self.repo()?.status_file(Path::new(path))?;
+            let state = if status.is_empty() || status == Status::IGNORED {
+                "unchanged"
+            } else if status.intersects(Status::WT_DELETED | Status::INDEX_DELETED) {
+                "deleted"
+            } else {
+                "modified since HEAD"
+            };
+
+            println!("{}: {}", path.bright_yellow(), state.bright_cyan());
+        }
+
+        println!(
+            "{}: {}",
+            "Auto sync".bright_yellow(),
+            if self.config.auto_sync() { "on" } else { "off" }.bright_cyan()
+        );
+
+        let pending = match self.config.pending_push() {
+            Some(since) => format!(
+                "{} commit(s) queued since {since}",
+                self.unpushed_commits()?
+            ),
+            None => "None".to_string(),
+        };
+        println!(
+            "{}: {}",
+            "Pending push".bright_yellow(),
+            pending.bright_cyan()
+        );
+
+        if let Some(remote_oid) = remote_oid.filter(|&oid| oid != local_oid) {
+            self.print_remote_changes(remote_oid)?;
+        }
+
+        Ok(())
+    }
+
+    fn print_remote_changes(&self, remote_oid: Oid) -> Result<()> {
+        let remote_tree = self.repo()?.find_commit(remote_oid)?.tree()?;
+
+        let Ok((_, remote_user)) = self.user_at(&remote_tree) else {
+            println!(
+                "{}",
+                "Remote store is encrypted with a different key, pull to compare items"
+                    .bright_yellow()
+            );
+            return Ok(());
+        };
+        let (remote_store, _) = self.store_at(&remote_tree, &mut vec![self.store_aes.clone()])?;
+
+        let changes = diff(
+            &self.store.decrypt_items(&self.store_aes)?,
+            &remote_store.decrypt_items(&self.store_aes)?,
+        )
+        .concat();
+        let user_changes = diff(&self.user.to_hashmap(), &remote_user.to_hashmap()).concat();
+
+        if changes.is_empty() && user_changes.is_empty() {
+            println!("{}", "Items match the remote".bright_green());
+            return Ok(());
+        }
+
+        println!("{}", "Remote compared to local:".bright_yellow());
+        for change in changes {
+            println!("  {}", change.bright_cyan());
+        }
+        for change in user_changes {
+            println!("  user {}", change.bright_cyan());
+        }
+
+        Ok(())
+    }
+
+    fn unpushed_commits(&self) -> Result<usize> {
+        let local_oid = self.repo()?.head()?.peel_to_commit()?.id();
+
+        if let Ok(remote_oid) = self.repo()?.refname_to_id(REMOTE_MAIN_REF) {
+            return Ok(self.repo()?.graph_ahead_behind(local_oid, remote_oid)?.0);
+        }
+
+        let mut revwalk = self.repo()?.revwalk()?;
+        revwalk.push(local_oid)?;
+
+        Ok(revwalk.count())
+    }
+}
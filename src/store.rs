@@ -1,22 +1,40 @@
-use std::{fs::File, path::PathBuf};
+use std::path::{Path, PathBuf};
 
 use aes_gcm::{
     aead::{Aead, KeyInit},
     Aes256Gcm,
 };
 use argon2::Argon2;
-use dialoguer::{theme::ColorfulTheme, Confirm, MultiSelect, Password};
-use git2::{Cred, Direction, PushOptions, RemoteCallbacks, Repository};
+use chrono::Local;
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Password, Select};
+use git2::build::CheckoutBuilder;
 use hashbrown::HashMap;
+use owo_colors::OwoColorize;
+use rkyv::AlignedVec;
 use snafu::ResultExt;
 
 use crate::{
+    archive::ARCHIVE_PATH,
+    atomic::write_with_backup,
+    backend::remote_ref,
     cmd::SyncDirection,
-    diff::{diff, Item as DiffItem},
-    error::{FsErr, Result},
-    manager::{length_validator, Manager, ORIGIN, STORE_BIN_PATH},
-    user::Credentials,
+    diff::{diff, merge, Conflict},
+    erase::Wiper,
+    error::{CorruptStoreErr, FsErr, Result, UndecryptableErr},
+    journal::JOURNAL_PATH,
+    layout::ITEMS_DIR,
+    manager::{
+        length_validator, read_blob, Manager, CLONE_DIR_PREFIX, ORIGIN, REMOTE_MAIN_REF,
+        STORE_BIN_PATH, USER_BIN_PATH,
+    },
+    message::CommitMessage,
+    oplog::OPS_DIR,
+    user::User,
 };
+
+/// Has to be typed to nuke the store.
+const NUKE_CONFIRMATION: &str = "nuke";
+
 #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, PartialEq, Eq)]
 #[archive(check_bytes)]
 pub struct Item {
@@ -25,7 +43,7 @@ pub struct Item {
 }
 
 impl Item {
-    pub fn new(nonce: [u8; 12], password: Vec<u8>) -> Self {
+    pub const fn new(nonce: [u8; 12], password: Vec<u8>) -> Self {
         Self { nonce, password }
     }
 }
@@ -53,18 +71,39 @@ impl Store {
         let buf = std::fs::read(path).context(FsErr {
             path: path.display().to_string(),
         })?;
-        let bin = rkyv::from_bytes::<Self>(&buf).map_err(|err| err.to_string())?;
 
-        Ok(bin)
+        // the raw rkyv error says nothing about what to do next
+        Self::from_bytes(&buf).map_err(|_| {
+            CorruptStoreErr {
+                path: path.display().to_string(),
+            }
+            .build()
+        })
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let mut aligned = AlignedVec::with_capacity(buf.len());
+        aligned.extend_from_slice(buf);
+
+        Ok(rkyv::from_bytes::<Self>(&aligned).map_err(|err| err.to_string())?)
     }
 
-    pub fn save(&self, path: &PathBuf) -> Result<()> {
+    pub fn save(&self, path: &Path) -> Result<()> {
         let data = rkyv::to_bytes::<_, 1024>(self).map_err(|err| err.to_string())?;
-        std::fs::write(path, &data).context(FsErr {
-            path: path.display().to_string(),
-        })?;
 
-        Ok(())
+        write_with_backup(path, &data)
+    }
+
+    pub fn decrypt_items(&self, cipher: &Aes256Gcm) -> Result<HashMap<String, Vec<u8>>> {
+        self.items
+            .iter()
+            .map(|(label, Item { nonce, password })| {
+                cipher
+                    .decrypt(nonce.into(), password.as_slice())
+                    .map(|plaintext| (label.clone(), plaintext))
+                    .map_err(|_| UndecryptableErr { label }.build())
+            })
+            .collect()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -123,117 +162,342 @@ impl Manager {
         Ok(())
     }
 
-    pub fn sync(&mut self, dir: SyncDirection, force: bool) -> Result<()> {
-        let Some(user_remote) = &self.user.remote else {
-            return Ok(println!("Remote not set"));
-        };
+    pub fn sync(&mut self, dir: SyncDirection, force: bool, remote: Option<&String>) -> Result<()> {
+        let names = self
+            .remotes()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+
+        if names.is_empty() {
+            self.repo()?;
+            println!("Remote not set");
+            return Ok(());
+        }
 
-        let mut remote = self.repo.find_remote(ORIGIN)?;
-        let mut cb = RemoteCallbacks::new();
-        if let Some(Credentials { username, password }) = &user_remote.creds {
-            cb.credentials(|_, _, _| Cred::userpass_plaintext(username, password));
+        if let Some(remote) = remote.filter(|remote| !names.contains(remote)) {
+            println!("{}", format!("No remote named '{remote}'").bright_red());
+            return Ok(());
         }
 
         match dir {
             SyncDirection::Push => {
-                remote.connect_auth(Direction::Push, Some(cb), None)?;
-
-                let mut push_options = PushOptions::new();
-                let mut push_cb = RemoteCallbacks::new();
-                if let Some(Credentials { username, password }) = &user_remote.creds {
-                    push_cb.credentials(|_, _, _| Cred::userpass_plaintext(username, password));
-                }
-                push_options.remote_callbacks(push_cb);
-
-                remote.push(
-                    &[if force {
-                        "+refs/heads/main:refs/heads/main"
-                    } else {
-                        "refs/heads/main:refs/heads/main"
-                    }],
-                    Some(&mut push_options),
-                )?;
-
-                self.success_message = Some("Successfully pushed store to remote".to_string());
+                let names = remote.map_or(names, |remote| vec![remote.clone()]);
+                self.push_all(&names, force)?;
             }
 
             SyncDirection::Pull => {
-                let temp_clone_dir = std::env::temp_dir().join("pm_remote");
-                std::fs::create_dir_all(&temp_clone_dir).context(FsErr {
-                    path: temp_clone_dir.display().to_string(),
-                })?;
+                let name = remote.map_or(ORIGIN, String::as_str);
+                self.fetch_from(name)?;
+                self.merge_remote(name)?;
+            }
+        }
 
-                Repository::clone(&user_remote.url, &temp_clone_dir)?;
+        Ok(())
+    }
 
-                let store = rkyv::from_bytes::<Store>(
-                    &std::fs::read(temp_clone_dir.join(STORE_BIN_PATH)).context(FsErr {
-                        path: temp_clone_dir.join(STORE_BIN_PATH).display().to_string(),
-                    })?,
-                )
-                .map_err(|err| err.to_string())?;
-
-                let store_diff_items = diff(&self.store.items, &store.items).concat();
-                let store_diff_indices = MultiSelect::with_theme(&ColorfulTheme::default())
-                    .with_prompt("Select changes to pull for store")
-                    .items(&store_diff_items)
-                    .interact()?;
-
-                let selected_store_items =
-                    get_values_from_indices(&store_diff_indices, &store_diff_items);
-
-                for item in selected_store_items {
-                    match item {
-                        DiffItem::Added(key) | DiffItem::Modified(key) => {
-                            let value = store.items[&key].clone();
-                            self.store.items.insert(key, value);
-                        }
-
-                        DiffItem::Deleted(key) => {
-                            self.store.items.remove(&key);
-                        }
+    /// Pushes to every remote in `names`, a failing one does not stop the
+    /// others.
+    fn push_all(&mut self, names: &[String], force: bool) -> Result<()> {
+        if let [name] = names {
+            self.push_to(name, force)?;
+            if name == ORIGIN {
+                self.config.set_pending_push(None)?;
+            }
+            self.success_message = Some("Successfully pushed store to remote".to_string());
+            return Ok(());
+        }
+
+        let mut pushed = 0;
+        for name in names {
+            match self.push_to(name, force) {
+                Ok(()) => {
+                    if name == ORIGIN {
+                        self.config.set_pending_push(None)?;
                     }
+                    println!("{}: {}", name.bright_yellow(), "pushed".bright_cyan());
+                    pushed += 1;
+                }
+                Err(err) => println!("{}: {}", name.bright_yellow(), err.bright_red()),
+            }
+        }
+
+        self.success_message = (pushed == names.len())
+            .then(|| format!("Successfully pushed store to {pushed} remotes"));
+        if pushed < names.len() {
+            println!(
+                "{}",
+                format!("Pushed store to {pushed} of {} remotes", names.len()).bright_yellow()
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn auto_sync(&mut self) -> Result<()> {
+        if self.config.pending_push().is_none() {
+            let now = Local::now().format("%e %b %y %H:%M").to_string();
+            self.config.set_pending_push(Some(now.trim()))?;
+        }
+
+        if let Err(err) = self.fetch() {
+            println!(
+                "{}",
+                format!("Push queued, remote unavailable: {err}").bright_yellow()
+            );
+            return Ok(());
+        }
+
+        let local_oid = self.repo()?.head()?.peel_to_commit()?.id();
+        if let Ok(remote_oid) = self.repo()?.refname_to_id(REMOTE_MAIN_REF) {
+            let (_, behind) = self.repo()?.graph_ahead_behind(local_oid, remote_oid)?;
+
+            if behind > 0 {
+                let message = self.success_message.take();
+
+                self.merge_parent = None;
+                self.fs_dirty = false;
+                self.merge_remote(ORIGIN)?;
+
+                if self.fs_dirty {
+                    self.commit(
+                        &CommitMessage::new("store", "merge", Some("origin/main")).to_string(),
+                    )?;
                 }
 
-                std::fs::remove_dir_all(&temp_clone_dir).context(FsErr {
-                    path: temp_clone_dir.display().to_string(),
-                })?;
+                if let Some(merged) = std::mem::replace(&mut self.success_message, message) {
+                    println!("{}", merged.bright_cyan());
+                }
+            }
+        }
+
+        if let Err(err) = self.push(false) {
+            println!(
+                "{}",
+                format!("Push queued, remote unavailable: {err}").bright_yellow()
+            );
+            return Ok(());
+        }
+
+        self.config.set_pending_push(None)
+    }
+
+    pub fn merge_remote(&mut self, name: &str) -> Result<()> {
+        let local_oid = self.repo()?.head()?.peel_to_commit()?.id();
+        let remote_oid = self
+            .repo()?
+            .find_reference(&remote_ref(name))?
+            .peel_to_commit()?
+            .id();
+        let base_oid = self.repo()?.merge_base(local_oid, remote_oid).ok();
+
+        if base_oid == Some(remote_oid) {
+            self.success_message = Some("Store is already up to date".to_string());
+            return Ok(());
+        }
+
+        let mut ciphers = vec![self.store_aes.clone()];
+
+        let remote_tree = self.repo()?.find_commit(remote_oid)?.tree()?;
+        let remote_user_blob = read_blob(self.repo()?, &remote_tree, USER_BIN_PATH)?;
+        let is_foreign = User::from_bytes(&remote_user_blob, &self.store_aes).is_err();
+
+        let (remote_store, remote_aes) = self.store_at(&remote_tree, &mut ciphers)?;
+        let remote_log = self.log_at(&remote_tree, &remote_aes)?;
+        drop(remote_tree);
+        let (remote_nonce, remote_user) = User::from_bytes(&remote_user_blob, &remote_aes)?;
+        let remote_items = remote_store.decrypt_items(&remote_aes)?;
+
+        if base_oid == Some(local_oid) && !is_foreign {
+            self.repo()?
+                .reference("refs/heads/main", remote_oid, true, "pm: fast-forward")?;
+            self.repo()?
+                .checkout_head(Some(CheckoutBuilder::new().force()))?;
+
+            self.store = remote_store;
+            self.user = remote_user;
+            self.user_nonce = remote_nonce;
+            self.load_layout()?;
+
+            self.success_message = Some("Successfully fast-forwarded store to remote".to_string());
+            return Ok(());
+        }
+
+        if let (Some(remote_log), true, false) = (remote_log, self.log.is_some(), is_foreign) {
+            return self.merge_log(remote_log, remote_oid);
+        }
+
+        let (base_items, base_user) = match base_oid {
+            Some(oid) => {
+                let tree = self.repo()?.find_commit(oid)?.tree()?;
+                let (base_store, base_aes) = self.store_at(&tree, &mut ciphers)?;
+                let base_user_blob = read_blob(self.repo()?, &tree, USER_BIN_PATH)?;
+
+                (
+                    base_store.decrypt_items(&base_aes)?,
+                    Some(User::from_bytes(&base_user_blob, &base_aes)?.1),
+                )
+            }
+            None => (HashMap::new(), None),
+        };
+
+        let local_items = self.store.decrypt_items(&self.store_aes)?;
+
+        let store_merge = merge(&base_items, &local_items, &remote_items);
+        let mut merged_items = store_merge.merged;
+
+        for Conflict { key, local, remote } in store_merge.conflicts {
+            let value = if take_remote(&key, local.is_some(), remote.is_some())? {
+                remote
+            } else {
+                local
+            };
+
+            if let Some(value) = value {
+                merged_items.insert(key, value);
+            }
+        }
 
-                self.success_message = Some("Successfully pulled store from remote".to_string());
-                todo!();
+        let user_merge = merge(
+            &base_user.map(|user| user.to_hashmap()).unwrap_or_default(),
+            &self.user.to_hashmap(),
+            &remote_user.to_hashmap(),
+        );
+        let mut fields = user_merge.merged;
+
+        for Conflict { key, local, remote } in user_merge.conflicts {
+            let value = if take_remote(&format!("user {key}"), local.is_some(), remote.is_some())? {
+                remote
+            } else {
+                local
+            };
+
+            if let Some(value) = value {
+                fields.insert(key, value);
             }
         }
 
+        for change in diff(&local_items, &merged_items).concat() {
+            println!("{}", change.bright_cyan());
+        }
+
+        let mut items = HashMap::new();
+        for (label, plaintext) in merged_items {
+            let item = if local_items.get(&label) == Some(&plaintext) {
+                self.store.items[&label].clone()
+            } else {
+                self.encrypt_item(&plaintext)?
+            };
+
+            items.insert(label, item);
+        }
+
+        self.store.items = items;
+        self.user.merge_fields(&fields, &remote_user);
+
+        self.merge_parent = Some(remote_oid);
+        self.fs_dirty = true;
+        self.success_message = Some("Successfully merged store from remote".to_string());
+
         Ok(())
     }
 
-    pub fn nuke(&mut self, sync: bool, archive: bool) -> Result<()> {
+    pub fn nuke(&mut self, sync: bool, archive: bool, wipe_git: bool) -> Result<()> {
+        let confirmation = Input::<String>::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!(
+                "This deletes the store and its history in {}. Type '{NUKE_CONFIRMATION}' to continue",
+                self.data_dir.display()
+            ))
+            .allow_empty(true)
+            .interact_text()?;
+
+        if confirmation.trim() != NUKE_CONFIRMATION {
+            println!("{}", "Nuke cancelled".bright_yellow());
+            return Ok(());
+        }
+
         if sync {
-            self.sync(SyncDirection::Push, true)?;
+            self.sync(SyncDirection::Push, true, None)?;
         }
 
+        self.backup()?;
+
         if archive {
-            let archive_file = File::create("pm.tar").context(FsErr {
-                path: "pm.tar".to_string(),
-            })?;
+            self.write_archive(Path::new(ARCHIVE_PATH))?;
+        }
 
-            let mut builder = tar::Builder::new(archive_file);
-            builder.append_dir_all("PassManager", &self.data_dir)?;
+        // the lock file can't be removed while it is open on windows
+        self.lock = None;
+        self.repo = None;
+
+        let mut wiper = Wiper::default();
+        for path in [
+            STORE_BIN_PATH,
+            USER_BIN_PATH,
+            &format!("{STORE_BIN_PATH}.bak"),
+            &format!("{USER_BIN_PATH}.bak"),
+            JOURNAL_PATH,
+        ] {
+            wiper.file(&self.data_dir.join(path));
+        }
+        for dir in [ITEMS_DIR, OPS_DIR] {
+            wiper.dir(&self.data_dir.join(dir));
+        }
 
-            builder.finish()?;
+        if wipe_git {
+            wiper.dir(&self.data_dir.join(".git"));
+
+            // older versions cloned the remote here to pull
+            wiper.dir(&std::env::temp_dir().join("pm_remote"));
+
+            // left behind by a clone that was interrupted
+            if let Some(parent) = self.data_dir.parent() {
+                for entry in std::fs::read_dir(parent).into_iter().flatten().flatten() {
+                    if entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(CLONE_DIR_PREFIX)
+                    {
+                        wiper.dir(&entry.path());
+                    }
+                }
+            }
         }
 
         std::fs::remove_dir_all(&self.data_dir).context(FsErr {
             path: self.data_dir.display().to_string(),
         })?;
+        wiper.report();
+        if !wipe_git {
+            println!(
+                "{}",
+                "Git objects were removed without overwriting them, see --wipe-git".bright_yellow()
+            );
+        }
 
-        self.success_message = Some("Successfully nuked the data".to_string());
+        self.success_message = Some(if self.config.backup_count() == 0 {
+            "Successfully nuked the data".to_string()
+        } else {
+            format!(
+                "Successfully nuked the data, backups are kept in {}",
+                self.backup_dir().display()
+            )
+        });
         Ok(())
     }
 }
 
-fn get_values_from_indices<T: Clone>(indices: &[usize], values: &[T]) -> Vec<T> {
-    indices
-        .iter()
-        .map(|&i| values[i].clone())
-        .collect::<Vec<_>>()
+fn take_remote(label: &str, local: bool, remote: bool) -> Result<bool> {
+    let describe = |present: bool| if present { "modified" } else { "deleted" };
+
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("Conflict on '{label}'"))
+        .items(&[
+            format!("keep local ({})", describe(local)),
+            format!("take remote ({})", describe(remote)),
+        ])
+        .default(0)
+        .interact()?;
+
+    Ok(selection == 1)
 }
@@ -1,4 +1,7 @@
-use std::io::{BufWriter, Write};
+use std::{
+    io::{IsTerminal, Write},
+    process::{Command, Stdio},
+};
 
 use owo_colors::OwoColorize;
 use snafu::OptionExt;
@@ -51,8 +54,53 @@ impl<const N: usize> Table<N> {
         self.rows.push(row);
     }
 
+    pub const fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
     pub fn display(self) -> Result<()> {
-        let mut buf = BufWriter::new(Vec::new());
+        std::io::stdout().write_all(&self.render()?)?;
+
+        Ok(())
+    }
+
+    /// Displays the table through `$PAGER` (or `less`) when stdout is a
+    /// terminal.
+    pub fn page(self) -> Result<()> {
+        let rendered = self.render()?;
+
+        if std::io::stdout().is_terminal() {
+            let pager = std::env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+            let mut parts = pager.split_whitespace();
+
+            if let Some(program) = parts.next() {
+                if let Ok(mut child) = Command::new(program)
+                    .args(parts)
+                    .env(
+                        "LESS",
+                        std::env::var("LESS").unwrap_or_else(|_| "FRX".to_string()),
+                    )
+                    .stdin(Stdio::piped())
+                    .spawn()
+                {
+                    if let Some(mut stdin) = child.stdin.take() {
+                        // the pager closing early is not an error
+                        let _ = stdin.write_all(&rendered);
+                    }
+                    child.wait()?;
+
+                    return Ok(());
+                }
+            }
+        }
+
+        std::io::stdout().write_all(&rendered)?;
+
+        Ok(())
+    }
+
+    fn render(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
 
         let (&last_max, rest_maxes) = self.maxes.split_last().context(SplitErr)?;
 
@@ -143,8 +191,6 @@ impl<const N: usize> Table<N> {
             BOTTOM_RIGHT_CORNER.bright_yellow()
         )?;
 
-        std::io::stdout().write_all(buf.buffer())?;
-
-        Ok(())
+        Ok(buf)
     }
 }
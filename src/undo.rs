@@ -0,0 +1,245 @@
+use aes_gcm::{aead::Aead, Aes256Gcm};
+use git2::{Commit, Oid};
+use hashbrown::{HashMap, HashSet};
+use owo_colors::OwoColorize;
+use snafu::OptionExt;
+
+use crate::{
+    diff::merge,
+    error::{InvalidCommitMessageUtf8Err, Result, RevertConflictErr},
+    manager::{Manager, ORIGIN},
+    message::CommitMessage,
+    store::{Item, Store},
+};
+
+#[derive(Default)]
+struct Stack {
+    undo: Option<Oid>,
+    redo: Option<Oid>,
+}
+
+impl Manager {
+    pub fn undo(&mut self, id: Option<&String>) -> Result<()> {
+        if self.repo.is_none() {
+            return self.undo_plain(id);
+        }
+
+        if self.log.is_some() {
+            return self.undo_op(id);
+        }
+
+        let target = if let Some(id) = id {
+            self.resolve_revision(id)?.id()
+        } else if let Some(oid) = self.stack()?.undo {
+            oid
+        } else {
+            println!("{}", "Nothing to undo".bright_red());
+            return Ok(());
+        };
+
+        self.revert(target, "undo")
+    }
+
+    pub fn redo(&mut self) -> Result<()> {
+        if self.repo.is_none() {
+            return self.redo_plain();
+        }
+
+        if self.log.is_some() {
+            return self.redo_op();
+        }
+
+        let Some(target) = self.stack()?.redo else {
+            println!("{}", "Nothing to redo".bright_red());
+            return Ok(());
+        };
+
+        self.revert(target, "redo")
+    }
+
+    // Walks the first parent chain from HEAD. An undo cancels the commit it
+    // targets and a redo cancels the undo it targets; any other commit ends
+    // the redo stack.
+    fn stack(&self) -> Result<Stack> {
+        let mut revwalk = self.repo()?.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.simplify_first_parent()?;
+
+        let mut stack = Stack::default();
+        let mut cancelled = HashSet::new();
+        let mut redo_blocked = false;
+
+        for oid in revwalk {
+            let commit = self.repo()?.find_commit(oid?)?;
+            let message =
+                CommitMessage::parse(commit.message().context(InvalidCommitMessageUtf8Err)?);
+
+            match message.action.as_str() {
+                "undo" | "redo" if message.bin == "any" => {
+                    if cancelled.contains(&commit.id()) {
+                        continue;
+                    }
+
+                    if let Some(target) = self.target_of(&commit, &message) {
+                        cancelled.insert(target);
+                    }
+
+                    if message.action == "undo" && !redo_blocked && stack.redo.is_none() {
+                        stack.redo = Some(commit.id());
+                    }
+                }
+                _ => {
+                    redo_blocked = true;
+
+                    if !cancelled.contains(&commit.id()) {
+                        stack.undo = Some(commit.id());
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(stack)
+    }
+
+    // Undo commits made before trailers only recorded an optional short id,
+    // without one they reverted their parent. A target squashed away by
+    // store gc no longer cancels anything.
+    fn target_of(&self, commit: &Commit, message: &CommitMessage) -> Option<Oid> {
+        let repo = self.repo().ok()?;
+
+        if let Some(target) = &message.target {
+            return Oid::from_str(target)
+                .ok()
+                .filter(|&oid| repo.find_commit(oid).is_ok());
+        }
+
+        message
+            .value
+            .as_deref()
+            .and_then(|rev| repo.revparse_single(rev).ok())
+            .map(|object| object.id())
+            .or_else(|| commit.parent_id(0).ok())
+    }
+
+    fn revert(&mut self, target: Oid, action: &str) -> Result<()> {
+        let commit = self.repo()?.find_commit(target)?;
+        let short_id = commit
+            .as_object()
+            .short_id()?
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let Ok(parent) = commit.parent(0) else {
+            println!(
+                "{}",
+                "Nothing before store initialization to restore".bright_red()
+            );
+            return Ok(());
+        };
+
+        let mut ciphers = vec![self.store_aes.clone()];
+        let base = self.state_at(&commit, &mut ciphers)?;
+        let old = self.state_at(&parent, &mut ciphers)?;
+        drop((commit, parent));
+
+        let local_items = self.store.decrypt_items(&self.store_aes)?;
+        let local_fields = self.user.to_hashmap();
+        let old_fields = old.user.to_hashmap();
+
+        let items = merge(&base.items, &local_items, &old.items);
+        let fields = merge(&base.user.to_hashmap(), &local_fields, &old_fields);
+
+        let header = |store: &Store| (store.key.clone(), store.nonce, store.salt);
+        let header_changed = header(&base.store) != header(&old.store);
+        let header_conflict = header_changed && header(&self.store) != header(&base.store);
+
+        if !items.conflicts.is_empty() || !fields.conflicts.is_empty() || header_conflict {
+            let keys = items
+                .conflicts
+                .iter()
+                .map(|conflict| format!("'{}'", conflict.key))
+                .chain(
+                    fields
+                        .conflicts
+                        .iter()
+                        .map(|conflict| format!("user {}", conflict.key)),
+                )
+                .chain(header_conflict.then(|| "store key".to_string()))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            return Err(RevertConflictErr { id: short_id, keys }.build());
+        }
+
+        let mut restored = HashMap::new();
+        for (label, plaintext) in items.merged {
+            let item = match (old.store.items.get(&label), self.store.items.get(&label)) {
+                (Some(item), _)
+                    if old.items.get(&label) == Some(&plaintext)
+                        && decrypts(&self.store_aes, item) =>
+                {
+                    item.clone()
+                }
+                (_, Some(item)) if local_items.get(&label) == Some(&plaintext) => item.clone(),
+                _ => self.encrypt_item(&plaintext)?,
+            };
+
+            restored.insert(label, item);
+        }
+        self.store.items = restored;
+
+        if header_changed {
+            self.store.key = old.store.key;
+            self.store.nonce = old.store.nonce;
+            self.store.salt = old.store.salt;
+            println!(
+                "{}",
+                "Store key restored, use the previous key from now on".bright_yellow()
+            );
+        }
+
+        if fields.merged == old_fields {
+            self.user = old.user;
+            self.user_nonce = old.user_nonce;
+        } else {
+            self.user.merge_fields(&fields.merged, &old.user);
+        }
+        self.sync_origin()?;
+
+        self.fs_dirty = true;
+        self.commit_message = Some(
+            CommitMessage::new("any", action, Some(&short_id))
+                .with_target(&target.to_string())
+                .to_string(),
+        );
+        self.success_message = Some(format!(
+            "Successfully {} {short_id}",
+            if action == "undo" { "undid" } else { "redid" }
+        ));
+
+        Ok(())
+    }
+
+    pub fn sync_origin(&self) -> Result<()> {
+        let origin = self.repo()?.find_remote(ORIGIN).ok();
+
+        match (&self.user.remote, origin) {
+            (Some(remote), Some(origin)) if origin.url() != Some(remote.url.as_str()) => {
+                self.repo()?.remote_set_url(ORIGIN, &remote.url)?;
+            }
+            (Some(remote), None) => {
+                self.repo()?.remote(ORIGIN, &remote.url)?;
+            }
+            (None, Some(_)) => self.repo()?.remote_delete(ORIGIN)?,
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+pub fn decrypts(cipher: &Aes256Gcm, Item { nonce, password }: &Item) -> bool {
+    cipher.decrypt(nonce.into(), password.as_slice()).is_ok()
+}
@@ -1,21 +1,21 @@
-use std::{
-    io::prelude::*,
-    path::PathBuf,
-    process::{Command, Stdio},
-};
+use std::path::{Path, PathBuf};
 
 use aes_gcm::{aead::Aead, Aes256Gcm};
 use hashbrown::HashMap;
 use owo_colors::OwoColorize;
-use snafu::{OptionExt, ResultExt};
-use url::Url;
+use rkyv::AlignedVec;
+use snafu::ResultExt;
 
 use crate::{
-    error::{CommandErr, CredsErr, FsErr, HostErr, Result, SplitErr},
-    manager::{Manager, ORIGIN},
+    atomic::write_with_backup,
+    auth::{is_ssh, remote_host, CredentialHelper},
+    error::{FsErr, Result},
+    manager::{Manager, ORIGIN, REMOTE_MAIN_REF},
 };
 
-#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone)]
+const USER_MAGIC: &[u8; 4] = b"pmu\x02";
+
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, PartialEq, Eq)]
 #[archive(check_bytes)]
 pub struct Credentials {
     pub username: String,
@@ -47,26 +47,22 @@ impl User {
         }
     }
 
-    pub fn set_remote(&mut self, remote: &str, creds_required: Option<bool>) -> Result<()> {
+    pub fn set_remote(&mut self, remote: &str, cache_creds: Option<bool>) -> Result<()> {
         if remote == "-" {
             self.remote = None;
         } else {
-            let url = Url::parse(remote)?;
-
-            let host = url.host().context(HostErr)?.to_string();
+            let host = remote_host(remote)?;
+            let cache_creds = cache_creds.unwrap_or_else(|| {
+                self.remote
+                    .as_ref()
+                    .is_some_and(|remote| remote.creds.is_some())
+            });
 
             self.remote = Some(Remote {
-                host: host.clone(),
+                host,
                 url: remote.to_string(),
-                creds: if creds_required.unwrap_or_else(|| {
-                    self.remote
-                        .as_ref()
-                        .is_some_and(|remote| remote.creds.is_some())
-                }) {
-                    let (username, password) = get_remote_credentials(&host).map_err(|err| {
-                        git2::Error::from_str(&format!("Couldn't get credentials: {err}"))
-                    })?;
-                    Some(Credentials { username, password })
+                creds: if cache_creds && !is_ssh(remote) {
+                    Some(CredentialHelper::new(remote).fill()?)
                 } else {
                     None
                 },
@@ -76,29 +72,116 @@ impl User {
         Ok(())
     }
 
-    pub fn open(path: &PathBuf, cipher: &Aes256Gcm) -> Result<([u8; 12], Self)> {
+    pub fn set_cache_creds(&mut self, cache_creds: bool) -> Result<()> {
+        let Some(remote) = self.remote.as_mut() else {
+            println!("{}", "Remote not set".bright_red());
+            return Ok(());
+        };
+
+        remote.creds = if cache_creds && !is_ssh(&remote.url) {
+            Some(CredentialHelper::new(&remote.url).fill()?)
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+
+    // Credentials used to be stored in user.bin unconditionally. They are
+    // handed over to the git credential helper and only removed from the
+    // vault once the helper gives them back.
+    pub fn migrate_creds(&mut self) -> bool {
+        let Some(remote) = self.remote.as_mut() else {
+            return false;
+        };
+
+        let Some(creds) = remote.creds.clone() else {
+            return false;
+        };
+
+        let helper = CredentialHelper::new(&remote.url);
+        helper.store(creds.clone());
+        let stored = helper
+            .approve()
+            .and_then(|()| helper.lookup())
+            .is_ok_and(|found| found == creds);
+
+        if !stored {
+            println!(
+                "{}",
+                format!(
+                    "No git credential helper stored the credentials for {}, they stay in the vault (set credential.helper to move them)",
+                    remote.url
+                )
+                .bright_yellow()
+            );
+            return false;
+        }
+
+        remote.creds = None;
+
+        true
+    }
+
+    pub fn open(path: &PathBuf, cipher: &Aes256Gcm) -> Result<([u8; 12], Self, bool)> {
         let buf = std::fs::read(path).context(FsErr {
             path: path.display().to_string(),
         })?;
+
+        Self::decode(&buf, cipher)
+    }
+
+    pub fn from_bytes(buf: &[u8], cipher: &Aes256Gcm) -> Result<([u8; 12], Self)> {
+        let (nonce, user, _) = Self::decode(buf, cipher)?;
+
+        Ok((nonce, user))
+    }
+
+    fn decode(buf: &[u8], cipher: &Aes256Gcm) -> Result<([u8; 12], Self, bool)> {
         let (nonce_slice, ciphertext) = buf.split_at(12);
         let decrypted_buf = cipher.decrypt(nonce_slice.into(), ciphertext)?;
 
         let nonce: [u8; 12] = nonce_slice.try_into()?;
 
+        let (data, legacy) = decrypted_buf
+            .strip_prefix(USER_MAGIC)
+            .map_or((decrypted_buf.as_slice(), true), |data| (data, false));
+
+        let mut aligned = AlignedVec::with_capacity(data.len());
+        aligned.extend_from_slice(data);
+
         Ok((
             nonce,
-            rkyv::from_bytes::<Self>(&decrypted_buf).map_err(|err| err.to_string())?,
+            rkyv::from_bytes::<Self>(&aligned).map_err(|err| err.to_string())?,
+            legacy,
         ))
     }
 
-    pub fn save(&self, path: &PathBuf, cipher: &Aes256Gcm, nonce: [u8; 12]) -> Result<()> {
+    pub fn save(&self, path: &Path, cipher: &Aes256Gcm, nonce: [u8; 12]) -> Result<()> {
         let data = rkyv::to_bytes::<_, 1024>(self).map_err(|err| err.to_string())?;
-        let encrypted_data = cipher.encrypt(&nonce.into(), data.as_slice())?;
-        std::fs::write(path, [nonce.to_vec(), encrypted_data].concat()).context(FsErr {
-            path: path.display().to_string(),
-        })?;
+        let encrypted_data = cipher.encrypt(
+            &nonce.into(),
+            [USER_MAGIC.as_slice(), &data].concat().as_slice(),
+        )?;
+        write_with_backup(path, &[nonce.to_vec(), encrypted_data].concat())
+    }
 
-        Ok(())
+    pub fn merge_fields(&mut self, fields: &HashMap<String, String>, other: &Self) {
+        if let Some(name) = fields.get("name") {
+            self.name.clone_from(name);
+        }
+
+        if let Some(email) = fields.get("email") {
+            self.email.clone_from(email);
+        }
+
+        self.remote = fields.get("remote").and_then(|url| {
+            [&self.remote, &other.remote]
+                .into_iter()
+                .flatten()
+                .find(|remote| &remote.url == url)
+                .cloned()
+        });
     }
 
     pub fn to_hashmap(&self) -> HashMap<String, String> {
@@ -108,7 +191,7 @@ impl User {
         ]);
 
         if let Some(remote) = &self.remote {
-            map.insert("email".to_string(), remote.url.clone());
+            map.insert("remote".to_string(), remote.url.clone());
         }
 
         map
@@ -136,44 +219,54 @@ impl Manager {
 
     pub fn set_user(
         &mut self,
-        name: &Option<String>,
-        email: &Option<String>,
-        remote: &Option<String>,
-        creds_required: Option<bool>,
+        name: Option<&String>,
+        email: Option<&String>,
+        remote: Option<&String>,
+        cache_creds: Option<bool>,
     ) -> Result<()> {
         if let Some(name) = name {
-            self.user.name = name.clone();
+            self.user.name.clone_from(name);
         }
 
         if let Some(email) = email {
-            self.user.email = email.clone();
+            self.user.email.clone_from(email);
         }
 
         if let Some(remote) = remote {
-            if remote == "-" {
-                if self.repo.find_remote(ORIGIN).is_ok() {
-                    self.repo.remote_delete(ORIGIN)?;
+            // plain mode keeps the remote for a later switch to git
+            if let Some(repo) = &self.repo {
+                if remote == "-" {
+                    if repo.find_remote(ORIGIN).is_ok() {
+                        repo.remote_delete(ORIGIN)?;
+                    }
+                } else if repo.find_remote(ORIGIN).is_ok() {
+                    repo.remote_set_url(ORIGIN, remote)?;
+                } else {
+                    repo.remote(ORIGIN, remote)?;
+                }
+
+                if self.user.remote.as_ref().map(|remote| &remote.url) != Some(remote) {
+                    // the last known state belongs to the previous remote
+                    if let Ok(mut reference) = repo.find_reference(REMOTE_MAIN_REF) {
+                        reference.delete()?;
+                    }
+                    self.config.set_sync_version(ORIGIN, None)?;
                 }
-            } else if self.repo.find_remote(ORIGIN).is_ok() {
-                self.repo.remote_set_url(ORIGIN, remote)?;
-            } else {
-                self.repo.remote(ORIGIN, remote)?;
             }
 
-            self.user.set_remote(remote, creds_required)?;
+            self.user.set_remote(remote, cache_creds)?;
+        } else if let Some(cache_creds) = cache_creds {
+            self.user.set_cache_creds(cache_creds)?;
         }
 
         let fields = [
-            ("name", name),
-            ("email", email),
-            ("remote", remote),
-            (
-                "creds_required",
-                &creds_required.map(|value| value.to_string()),
-            ),
+            ("name", name.is_some()),
+            ("email", email.is_some()),
+            ("remote", remote.is_some()),
+            ("cache_creds", cache_creds.is_some()),
         ]
         .iter()
-        .filter_map(|(name, el)| el.is_some().then_some(*name))
+        .filter_map(|&(name, is_set)| is_set.then_some(name))
         .collect::<Vec<_>>()
         .join(", ");
 
@@ -183,43 +276,3 @@ impl Manager {
         Ok(())
     }
 }
-
-pub fn get_remote_credentials(host: &str) -> Result<(String, String)> {
-    let command = Command::new("git")
-        .args(["credential", "fill"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    command
-        .stdin
-        .context(CommandErr { fd: "stdin" })?
-        .write_all(format!("protocol=https\nhost={host}").as_bytes())?;
-
-    let mut s = String::new();
-    command
-        .stdout
-        .context(CommandErr { fd: "stdin" })?
-        .read_to_string(&mut s)?;
-
-    let mut creds = (None, None);
-
-    for line in s.split_terminator('\n') {
-        let (k, v) = line.split_once('=').context(SplitErr)?;
-        if k == "username" {
-            creds.0 = Some(v.to_string());
-        } else if k == "password" {
-            creds.1 = Some(v.to_string());
-        }
-    }
-
-    match creds {
-        (Some(u), Some(p)) => Ok((u, p)),
-        (None, Some(_)) => Err(CredsErr { key: "username" }.build()),
-        (Some(_), None) => Err(CredsErr { key: "password" }.build()),
-        (None, None) => Err(CredsErr {
-            key: "username, password",
-        }
-        .build()),
-    }
-}
@@ -0,0 +1,454 @@
+use std::path::{Path, PathBuf};
+
+use aes_gcm::{aead::Aead, Aes256Gcm};
+use dialoguer::{theme::ColorfulTheme, Confirm, Password};
+use git2::{Repository, Sort};
+use owo_colors::OwoColorize;
+use rkyv::AlignedVec;
+use snafu::ResultExt;
+
+use crate::{
+    atomic::write_atomic,
+    config::Config,
+    error::{DamagedErr, FsErr, Result},
+    layout::{item_files, INDEX_NAME, ITEMS_DIR},
+    lock::{Access, DataLock},
+    manager::{format_time, length_validator, unlock, STORE_BIN_PATH, USER_BIN_PATH},
+    oplog::{OpLog, OPS_DIR},
+    revision::short_id,
+    store::{ArchivedStore, Store},
+    undo::decrypts,
+    user::User,
+};
+
+/// Length of the data key once wrapped, the key and the AES-GCM tag.
+const WRAPPED_KEY_LEN: usize = 48;
+
+/// Checks the store files from the header up to every item, then the git
+/// objects. Runs without a manager, as opening one fails on a corrupt store.
+pub fn verify(data_dir: &Path, repair: bool) -> Result<String> {
+    let access = if repair {
+        Access::Exclusive
+    } else {
+        Access::Shared
+    };
+    let _lock = DataLock::acquire(data_dir, access, Config::open(data_dir)?.lock_timeout())?;
+
+    let mut verifier = Verifier {
+        data_dir: data_dir.to_path_buf(),
+        repo: None,
+        repair,
+        problems: 0,
+        repaired: 0,
+    };
+
+    let repo_err = if data_dir.join(".git").exists() {
+        match Repository::open(data_dir) {
+            Ok(repo) => {
+                verifier.repo = Some(repo);
+                None
+            }
+            Err(err) => Some(err),
+        }
+    } else {
+        None
+    };
+
+    if let Some(store) = verifier.check_store()? {
+        if let Some((store, cipher)) = verifier.check_key(store)? {
+            verifier.check_user(&cipher)?;
+            verifier.check_items(store, &cipher)?;
+        }
+    }
+
+    if let Some(err) = repo_err {
+        verifier.fail("Git repository", err.message());
+    } else {
+        verifier.check_git();
+    }
+
+    let left = verifier.problems - verifier.repaired;
+    if left > 0 {
+        return Err(DamagedErr { problems: left }.build());
+    }
+
+    Ok(if verifier.repaired > 0 {
+        format!("Successfully repaired {} problem(s)", verifier.repaired)
+    } else {
+        "Store is intact".to_string()
+    })
+}
+
+struct Verifier {
+    data_dir: PathBuf,
+    repo: Option<Repository>,
+    repair: bool,
+    problems: usize,
+    repaired: usize,
+}
+
+impl Verifier {
+    fn pass(check: &str, detail: &str) {
+        println!("{}: {}", check.bright_yellow(), detail.bright_green());
+    }
+
+    fn fail(&mut self, check: &str, detail: &str) {
+        self.problems += 1;
+        println!("{}: {}", check.bright_yellow(), detail.bright_red());
+    }
+
+    fn read(&self, path: &str) -> Vec<u8> {
+        std::fs::read(self.data_dir.join(path)).unwrap_or_default()
+    }
+
+    fn check_store(&mut self) -> Result<Option<Store>> {
+        let buf = self.read(STORE_BIN_PATH);
+
+        if let Some(problem) = store_problem(&buf) {
+            self.fail("Store file", problem);
+
+            return self
+                .recover(STORE_BIN_PATH, STORE_BIN_PATH, |data| {
+                    store_problem(data).is_none()
+                })?
+                .map(|data| Store::from_bytes(&data))
+                .transpose();
+        }
+
+        Self::pass("Store file", "header and archive are valid");
+
+        Ok(Some(Store::from_bytes(&buf)?))
+    }
+
+    fn check_key(&mut self, store: Store) -> Result<Option<(Store, Aes256Gcm)>> {
+        let key = Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Your key")
+            .validate_with(|inp: &String| length_validator(inp))
+            .interact()?;
+
+        if let Ok((_, cipher)) = unlock(&store, &key) {
+            Self::pass("Data key", "unwraps");
+            return Ok(Some((store, cipher)));
+        }
+
+        self.fail(
+            "Data key",
+            "doesn't unwrap, the key is wrong or the header is damaged",
+        );
+
+        let intact =
+            |data: &[u8]| Store::from_bytes(data).is_ok_and(|store| unlock(&store, &key).is_ok());
+
+        self.recover(STORE_BIN_PATH, STORE_BIN_PATH, intact)?
+            .map(|data| {
+                let store = Store::from_bytes(&data)?;
+                let (_, cipher) = unlock(&store, &key)?;
+
+                Ok((store, cipher))
+            })
+            .transpose()
+    }
+
+    fn check_user(&mut self, cipher: &Aes256Gcm) -> Result<()> {
+        let intact = |data: &[u8]| data.len() >= 12 && User::from_bytes(data, cipher).is_ok();
+
+        if intact(&self.read(USER_BIN_PATH)) {
+            Self::pass("User data", "decrypts");
+        } else {
+            self.fail("User data", "doesn't decrypt");
+            self.recover(USER_BIN_PATH, USER_BIN_PATH, intact)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_items(&mut self, store: Store, cipher: &Aes256Gcm) -> Result<()> {
+        if self.data_dir.join(OPS_DIR).exists() {
+            self.check_ops(cipher)
+        } else if self.data_dir.join(ITEMS_DIR).join(INDEX_NAME).exists() {
+            self.check_split(cipher)
+        } else {
+            self.check_single(store, cipher)
+        }
+    }
+
+    fn check_ops(&mut self, cipher: &Aes256Gcm) -> Result<()> {
+        let ops_dir = self.data_dir.join(OPS_DIR);
+        let mut names = vec![];
+        for entry in std::fs::read_dir(&ops_dir).context(FsErr {
+            path: ops_dir.display().to_string(),
+        })? {
+            names.push(entry?.file_name().to_string_lossy().to_string());
+        }
+
+        let mut intact_count = 0;
+        for name in names {
+            let path = format!("{OPS_DIR}/{name}");
+            let intact = |data: &[u8]| OpLog::is_intact(&name, data, cipher);
+
+            if intact(&self.read(&path)) {
+                intact_count += 1;
+            } else {
+                self.fail(&format!("Record {name}"), "doesn't decrypt");
+                self.recover(&path, &format!("record {name}"), intact)?;
+            }
+        }
+
+        Self::pass(
+            "Operation log",
+            &format!("{intact_count} record(s) decrypt"),
+        );
+
+        Ok(())
+    }
+
+    fn check_split(&mut self, cipher: &Aes256Gcm) -> Result<()> {
+        let index_path = format!("{ITEMS_DIR}/{INDEX_NAME}");
+        let files = if let Ok(files) = item_files(&self.read(&index_path), cipher) {
+            files
+        } else {
+            self.fail("Item index", "doesn't decrypt");
+
+            let Some(index) = self.recover(&index_path, "the item index", |data| {
+                item_files(data, cipher).is_ok()
+            })?
+            else {
+                return Ok(());
+            };
+            item_files(&index, cipher)?
+        };
+
+        let mut intact_count = 0;
+        for (label, name) in files {
+            let path = format!("{ITEMS_DIR}/{name}");
+            let intact = |data: &[u8]| {
+                data.len() >= 12 && {
+                    let (nonce, ciphertext) = data.split_at(12);
+                    cipher.decrypt(nonce.into(), ciphertext).is_ok()
+                }
+            };
+
+            if intact(&self.read(&path)) {
+                intact_count += 1;
+            } else {
+                self.fail(&format!("Item '{label}'"), "is missing or doesn't decrypt");
+                self.recover(&path, &format!("item '{label}'"), intact)?;
+            }
+        }
+
+        Self::pass("Items", &format!("{intact_count} item(s) decrypt"));
+
+        Ok(())
+    }
+
+    fn check_single(&mut self, mut store: Store, cipher: &Aes256Gcm) -> Result<()> {
+        let mut damaged = store
+            .items
+            .iter()
+            .filter(|(_, item)| !decrypts(cipher, item))
+            .map(|(label, _)| label.clone())
+            .collect::<Vec<_>>();
+        damaged.sort();
+
+        let mut recovered = false;
+        for label in &damaged {
+            self.fail(&format!("Item '{label}'"), "doesn't decrypt");
+
+            // the rest of the store may have changed since, only the item is taken
+            let item_at = |data: &[u8]| {
+                Store::from_bytes(data)
+                    .ok()
+                    .and_then(|store| store.items.get(label).cloned())
+                    .filter(|item| decrypts(cipher, item))
+            };
+
+            let Some(data) =
+                self.find_intact(STORE_BIN_PATH, &format!("item '{label}'"), |data| {
+                    item_at(data).is_some()
+                })?
+            else {
+                continue;
+            };
+
+            if let Some(item) = item_at(&data) {
+                store.items.insert(label.clone(), item);
+                self.repaired += 1;
+                recovered = true;
+            }
+        }
+
+        if recovered {
+            store.save(&self.data_dir.join(STORE_BIN_PATH))?;
+        }
+
+        Self::pass(
+            "Items",
+            &format!("{} item(s) decrypt", store.items.len() - damaged.len()),
+        );
+
+        Ok(())
+    }
+
+    fn check_git(&mut self) {
+        let Some(repo) = &self.repo else {
+            return;
+        };
+
+        let mut count = 0;
+        let mut damaged = vec![];
+        let result = repo.odb().and_then(|odb| {
+            odb.foreach(|oid| {
+                count += 1;
+                if odb.read(*oid).is_err() {
+                    damaged.push(*oid);
+                }
+                true
+            })
+        });
+
+        // objects that are referenced but gone don't show up in the object store
+        let broken_history = repo
+            .revwalk()
+            .and_then(|mut revwalk| {
+                if repo.head().is_err() {
+                    return Ok(());
+                }
+
+                revwalk.push_head()?;
+                for oid in revwalk {
+                    repo.find_commit(oid?)?.tree()?;
+                }
+                Ok(())
+            })
+            .err();
+
+        match result {
+            Err(err) => self.fail("Git objects", err.message()),
+            Ok(()) if damaged.is_empty() => {
+                Self::pass("Git objects", &format!("{count} object(s) intact"));
+            }
+            Ok(()) => {
+                for oid in damaged {
+                    self.fail("Git object", &format!("{oid} is corrupt"));
+                }
+            }
+        }
+
+        if let Some(err) = broken_history {
+            self.fail("Git history", err.message());
+        }
+    }
+
+    /// Offers to replace the file at `path`, holding `what`, with its newest
+    /// version in history that `intact` accepts, returning the recovered
+    /// contents.
+    fn recover(
+        &mut self,
+        path: &str,
+        what: &str,
+        intact: impl Fn(&[u8]) -> bool,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(data) = self.find_intact(path, what, intact)? else {
+            return Ok(None);
+        };
+
+        write_atomic(&self.data_dir.join(path), &data)?;
+        self.repaired += 1;
+
+        Ok(Some(data))
+    }
+
+    /// Newest version of the file at `path` in history that `intact`
+    /// accepts, once the user agrees to recover `what` from it.
+    fn find_intact(
+        &self,
+        path: &str,
+        what: &str,
+        intact: impl Fn(&[u8]) -> bool,
+    ) -> Result<Option<Vec<u8>>> {
+        if !self.repair {
+            return Ok(None);
+        }
+
+        let Some(repo) = &self.repo else {
+            println!(
+                "  {}",
+                "no git history to recover from, see `pm store backups`".bright_red()
+            );
+            return Ok(None);
+        };
+
+        let Some((data, id, time)) = newest_intact(repo, path, intact) else {
+            println!(
+                "  {}",
+                format!("no intact version of {what} in history").bright_red()
+            );
+            return Ok(None);
+        };
+
+        if !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Recover {what} from {id} ({})?", time.trim()))
+            .interact()?
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(data))
+    }
+}
+
+/// What is wrong with the contents of `pm_store.bin`, if anything.
+fn store_problem(buf: &[u8]) -> Option<&'static str> {
+    if buf.len() < std::mem::size_of::<ArchivedStore>() {
+        return Some("missing or truncated");
+    }
+
+    let mut aligned = AlignedVec::with_capacity(buf.len());
+    aligned.extend_from_slice(buf);
+
+    let Ok(archived) = rkyv::check_archived_root::<Store>(&aligned) else {
+        return Some("archive fails validation");
+    };
+
+    (archived.key.len() != WRAPPED_KEY_LEN).then_some("header holds no valid data key")
+}
+
+/// Walks every commit reachable from a ref, newest first, skipping objects
+/// that can't be read.
+fn newest_intact(
+    repo: &Repository,
+    path: &str,
+    intact: impl Fn(&[u8]) -> bool,
+) -> Option<(Vec<u8>, String, String)> {
+    let mut revwalk = repo.revwalk().ok()?;
+    revwalk.set_sorting(Sort::TIME).ok()?;
+    let _ = revwalk.push_head();
+    let _ = revwalk.push_glob("*");
+
+    for oid in revwalk.flatten() {
+        let Ok(commit) = repo.find_commit(oid) else {
+            continue;
+        };
+
+        let Some(data) = commit
+            .tree()
+            .ok()
+            .and_then(|tree| tree.get_path(Path::new(path)).ok())
+            .and_then(|entry| entry.to_object(repo).ok())
+            .and_then(|object| object.into_blob().ok())
+            .map(|blob| blob.content().to_vec())
+        else {
+            continue;
+        };
+
+        if intact(&data) {
+            return Some((
+                data,
+                short_id(&commit).ok()?,
+                format_time(commit.time()).ok()?,
+            ));
+        }
+    }
+
+    None
+}
@@ -0,0 +1,141 @@
+use std::cell::RefCell;
+
+use base64ct::{Base64, Encoding};
+
+use crate::{
+    auth::CredentialHelper,
+    backend::{read_body, send, BundleStore},
+    error::{HttpErr, Result, SyncConflictErr},
+    user::Credentials,
+};
+
+const BUNDLE_NAME: &str = "pm.bundle";
+
+/// Bundle on a `WebDAV` server, versioned by its `ETag`.
+pub struct WebDavStore {
+    /// Collection holding the bundle, with a trailing slash.
+    collection: String,
+    helper: CredentialHelper,
+    authorization: RefCell<Option<String>>,
+}
+
+impl WebDavStore {
+    /// `webdav://host/path` is served over http, `webdavs://` over https.
+    pub fn new(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("webdav")
+            .ok_or(url::ParseError::RelativeUrlWithoutBase)?;
+        let collection = format!("http{}/", rest.trim_end_matches('/'));
+        url::Url::parse(&collection)?;
+
+        Ok(Self {
+            collection,
+            helper: CredentialHelper::new(url),
+            authorization: RefCell::new(None),
+        })
+    }
+
+    fn bundle_url(&self) -> String {
+        format!("{}{BUNDLE_NAME}", self.collection)
+    }
+
+    /// Sends the request, asking the credential helper for a login when the
+    /// server wants one.
+    fn request(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<ureq::Response> {
+        let build = || {
+            let mut request = ureq::request(method, url);
+            for (name, value) in headers {
+                request = request.set(name, value);
+            }
+            if let Some(authorization) = self.authorization.borrow().as_deref() {
+                request = request.set("Authorization", authorization);
+            }
+            request
+        };
+
+        let response = send(build(), body)?;
+        if response.status() != 401 || self.authorization.borrow().is_some() {
+            return Ok(response);
+        }
+
+        let Credentials { username, password } = self.helper.fill()?;
+        *self.authorization.borrow_mut() = Some(format!(
+            "Basic {}",
+            Base64::encode_string(format!("{username}:{password}").as_bytes())
+        ));
+
+        let response = send(build(), body)?;
+        if response.status() == 401 {
+            self.helper.reject();
+        } else {
+            let _ = self.helper.approve();
+        }
+
+        Ok(response)
+    }
+}
+
+impl BundleStore for WebDavStore {
+    fn get(&self) -> Result<Option<(Vec<u8>, String)>> {
+        let response = self.request("GET", &self.bundle_url(), &[], &[])?;
+        if response.status() == 404 {
+            return Ok(None);
+        }
+
+        let etag = response.header("ETag").map(ToString::to_string);
+        let bundle = read_body(response)?;
+
+        let version = etag.ok_or_else(|| {
+            HttpErr {
+                message: "server sent no ETag for the bundle",
+            }
+            .build()
+        })?;
+
+        Ok(Some((bundle, version)))
+    }
+
+    fn put(&self, bundle: &[u8], expected: Option<&str>, force: bool) -> Result<String> {
+        let condition = match (force, expected) {
+            (true, _) => vec![],
+            (false, Some(version)) => vec![("If-Match", version)],
+            (false, None) => vec![("If-None-Match", "*")],
+        };
+
+        let mut response = self.request("PUT", &self.bundle_url(), &condition, bundle)?;
+
+        // the collection is missing before the first push
+        if response.status() == 409 {
+            read_body(self.request("MKCOL", &self.collection, &[], &[])?)?;
+            response = self.request("PUT", &self.bundle_url(), &condition, bundle)?;
+        }
+
+        if response.status() == 412 {
+            return Err(SyncConflictErr.build());
+        }
+
+        let etag = response.header("ETag").map(ToString::to_string);
+        read_body(response)?;
+
+        if let Some(etag) = etag {
+            return Ok(etag);
+        }
+
+        let response = self.request("HEAD", &self.bundle_url(), &[], &[])?;
+        let etag = response.header("ETag").map(ToString::to_string);
+        read_body(response)?;
+
+        etag.ok_or_else(|| {
+            HttpErr {
+                message: "server sent no ETag for the bundle",
+            }
+            .build()
+        })
+    }
+}